@@ -0,0 +1,178 @@
+//! Settings from `$RBM_BASE/.rbm.json`. The file and every key in it are
+//! optional:
+//!
+//! ```json
+//! {
+//!     "browser": "firefox --new-tab",
+//!     "html_order": "file",
+//!     "redirect": "http://127.0.0.1:8080/go/",
+//!     "url": {
+//!         "ignore_scheme": true,
+//!         "drop_fragment": true,
+//!         "strip_params": ["utm_*", "fbclid", "gclid", "ref"]
+//!     }
+//! }
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use urls::UrlRules;
+use {base_dir, Error, Order, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub url_rules: UrlRules,
+    /// Command `open` runs with each url, overriding `$BROWSER`: the program
+    /// and its arguments
+    pub browser: Option<Vec<String>>,
+    /// Tile order in bm.html, most used first unless set to `file`
+    pub html_order: Order,
+    /// Where bm.html tiles link, followed by the hash, so `rbm serve` can
+    /// count the visit. Tiles link straight to the url without one.
+    pub redirect: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config{
+            url_rules: UrlRules::default(),
+            browser: None,
+            html_order: Order::Frecency,
+            redirect: None,
+        }
+    }
+}
+
+fn invalid(path: &str, key: &str, expected: &str) -> Error {
+    Error::Config(format!("{}: {} should be {}", path, key, expected))
+}
+
+impl Config {
+    /// Reads `.rbm.json` from the base directory, or the defaults if there
+    /// isn't one
+    pub fn load() -> Result<Config> {
+        let path = format!("{}/.rbm.json", base_dir()?);
+        if !Path::new(&path).exists() {
+            return Ok(Config::default())
+        }
+        Config::parse(&fs::read_to_string(&path)?, &path)
+    }
+
+    pub fn from_json(json: &str) -> Result<Config> {
+        Config::parse(json, ".rbm.json")
+    }
+
+    /// Errors name `path` and the key that's wrong
+    fn parse(json: &str, path: &str) -> Result<Config> {
+        let invalid = |key: &str, expected: &str| invalid(path, key, expected);
+        let value: Value = serde_json::from_str(json)
+            .map_err(|e| Error::Config(format!("{}: {}", path, e)))?;
+        let mut config = Config::default();
+
+        if let Some(browser) = value.get("browser") {
+            let command = match *browser {
+                Value::String(ref command) => Some(split_command(command)),
+                Value::Array(ref args) => args.iter().map(|a| a.as_str().map(String::from)).collect(),
+                _ => None
+            };
+            config.browser = Some(command.filter(|c: &Vec<String>| !c.is_empty())
+                                  .ok_or_else(|| invalid("browser", "a command or a list of arguments"))?);
+        }
+        if let Some(order) = value.get("html_order") {
+            config.html_order = order.as_str().and_then(|o| Order::parse(o).ok())
+                .ok_or_else(|| invalid("html_order", "\"file\" or \"frecency\""))?;
+        }
+        if let Some(redirect) = value.get("redirect") {
+            config.redirect = Some(redirect.as_str().map(String::from)
+                                   .ok_or_else(|| invalid("redirect", "a url"))?);
+        }
+
+        let url = &value["url"];
+        if let Some(ignore_scheme) = url.get("ignore_scheme") {
+            config.url_rules.ignore_scheme = ignore_scheme.as_bool()
+                .ok_or_else(|| invalid("url.ignore_scheme", "true or false"))?;
+        }
+        if let Some(drop_fragment) = url.get("drop_fragment") {
+            config.url_rules.drop_fragment = drop_fragment.as_bool()
+                .ok_or_else(|| invalid("url.drop_fragment", "true or false"))?;
+        }
+        if let Some(params) = url.get("strip_params") {
+            config.url_rules.strip_params = params.as_array()
+                .and_then(|params| params.iter().map(|p| p.as_str().map(String::from)).collect())
+                .ok_or_else(|| invalid("url.strip_params", "a list of parameter names"))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Splits a command line into words as a shell would, keeping spaces inside
+/// quotes or after a backslash
+pub fn split_command(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                if let Some(next) = chars.next() {
+                    word.get_or_insert_with(String::new).push(next);
+                }
+            },
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            },
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c)
+        }
+    }
+    words.extend(word);
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_json_test() {
+        assert_eq!(Config::default(), Config::from_json("{}").unwrap());
+
+        let config = Config::from_json("{\"browser\": \"firefox\", \"url\": {\"drop_fragment\": true, \"strip_params\": [\"ref\"]}}").unwrap();
+        assert_eq!(Some(vec![String::from("firefox")]), config.browser);
+        assert!(config.url_rules.drop_fragment);
+        assert_eq!(vec!["ref"], config.url_rules.strip_params);
+
+        assert!(Config::from_json("{\"url\": {\"strip_params\": \"ref\"}}").is_err());
+        assert!(Config::from_json("{\"url\": {\"ignore_scheme\": true}}").unwrap().url_rules.ignore_scheme);
+        match Config::parse("{\"url\": {\"ignore_scheme\": 1}}", "/home/me/.rbm.json") {
+            Err(Error::Config(e)) => assert_eq!("/home/me/.rbm.json: url.ignore_scheme should be true or false", e),
+            other => panic!("Unexpected {:?}", other)
+        }
+        assert!(Config::from_json("not json").is_err());
+
+        let config = Config::from_json("{\"html_order\": \"file\", \"redirect\": \"http://localhost:8080/go/\"}").unwrap();
+        assert_eq!(Order::File, config.html_order);
+        assert_eq!(Some(String::from("http://localhost:8080/go/")), config.redirect);
+        assert!(Config::from_json("{\"html_order\": \"newest\"}").is_err());
+
+        let config = Config::from_json("{\"browser\": [\"/opt/My Browser/browser\", \"%s\"]}").unwrap();
+        assert_eq!(Some(vec![String::from("/opt/My Browser/browser"), String::from("%s")]), config.browser);
+        assert!(Config::from_json("{\"browser\": \"\"}").is_err());
+        assert!(Config::from_json("{\"browser\": []}").is_err());
+    }
+
+    #[test]
+    fn split_command_test() {
+        assert_eq!(vec!["firefox", "--new-tab"], split_command("  firefox --new-tab "));
+        assert_eq!(vec!["/opt/My Browser/browser", "it's", ""], split_command("'/opt/My Browser/browser' it\\'s \"\""));
+        assert_eq!(vec!["My Browser", "a\"b"], split_command("My\\ Browser \"a\\\"b\""));
+        assert!(split_command("   ").is_empty());
+    }
+}
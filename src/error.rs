@@ -0,0 +1,91 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use reqwest;
+#[cfg(feature = "sqlite")]
+use rusqlite;
+use time;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A line, query or date that couldn't be read
+    Parse(String),
+    Io(io::Error),
+    /// Missing or invalid settings, such as an unset `RBM_BASE`
+    Config(String),
+    /// The page template couldn't be read, or a list template is invalid
+    Template(String),
+    Network(reqwest::Error),
+    #[cfg(feature = "sqlite")]
+    Database(rusqlite::Error),
+    /// Nothing matched a label, hash or url
+    NotFound(String),
+    /// More bookmarks matched a label, hash or url than the command allows
+    Ambiguous(String, usize),
+    /// The link is already saved under this label
+    Duplicate(String),
+    /// Another bookmark already has the label
+    LabelTaken(String),
+    /// Another rbm held the lock on a file for too long
+    Locked(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref e) => write!(f, "Parse error: {}", e),
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Config(ref e) => write!(f, "Configuration error: {}", e),
+            Error::Template(ref e) => write!(f, "Template error: {}", e),
+            Error::Network(ref e) => write!(f, "Network error: {}", e),
+            #[cfg(feature = "sqlite")]
+            Error::Database(ref e) => write!(f, "Database error: {}", e),
+            Error::NotFound(ref query) => write!(f, "No bookmark matches {}", query),
+            Error::Ambiguous(ref query, count) => write!(f, "{} bookmarks match {}", count, query),
+            Error::Duplicate(ref label) => write!(f, "Already saved as :{}", label),
+            Error::LabelTaken(ref label) => write!(f, "The label :{} is already used", label),
+            Error::Locked(ref path) => write!(f, "{} is in use by another rbm, try again", path),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Network(ref e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            Error::Database(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Network(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Database(e)
+    }
+}
+
+impl From<time::ParseError> for Error {
+    fn from(e: time::ParseError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
@@ -0,0 +1,279 @@
+//! Writing bookmarks out for other programs to read.
+
+use std::mem;
+
+use serde_json::{self, Value};
+
+use {escape_attribute, timestamp, Bookmark, Error, Result, WithImage};
+
+/// The fields of a serialized `Bookmark` and `image`, which templates can use
+pub const FIELDS: &[&str] = &["label", "hash", "url", "title", "tags", "created_at", "last_visited",
+                              "visit_count", "custom_image", "image", "description", "site_name",
+                              "canonical", "lang", "feeds"];
+
+/// The fields in csv and tsv output, in order
+const COLUMNS: &[&str] = &["label", "title", "url", "tags", "created_at", "last_visited", "visit_count", "image"];
+
+const NETSCAPE_HEADER: &str = "<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+";
+
+fn netscape_link(b: &Bookmark, indent: &str) -> String {
+    let mut link = format!("{}<DT><A HREF=\"{}\" ADD_DATE=\"{}\"", indent, escape_attribute(&b.url),
+                           timestamp(&b.created_at).sec);
+    if let Some(visited) = b.last_visited() {
+        link.push_str(&format!(" LAST_VISIT=\"{}\"", timestamp(visited).sec));
+    }
+    if !b.tags.is_empty() {
+        link.push_str(&format!(" TAGS=\"{}\"", escape_attribute(&b.tags.join(","))));
+    }
+    link.push_str(&format!(">{}</A>\n", escape_attribute(&b.title)));
+    if !b.metadata.description.is_empty() {
+        link.push_str(&format!("{}<DD>{}\n", indent, escape_attribute(&b.metadata.description)));
+    }
+    link
+}
+
+/// The bookmark HTML file browsers import. With `folders` each bookmark goes
+/// in a folder named after its first tag; either way `TAGS` lists them all.
+pub fn netscape(bookmarks: &[Bookmark], folders: bool) -> String {
+    let mut html = String::from(NETSCAPE_HEADER);
+    html.push_str("<DL><p>\n");
+
+    if folders {
+        let mut tags: Vec<&str> = Vec::new();
+        for tag in bookmarks.iter().filter_map(|b| b.tags.first()) {
+            if !tags.contains(&tag.as_str()) {
+                tags.push(tag);
+            }
+        }
+        for tag in tags {
+            html.push_str(&format!("    <DT><H3>{}</H3>\n    <DL><p>\n", escape_attribute(tag)));
+            for b in bookmarks.iter().filter(|b| b.tags.first().map(String::as_str) == Some(tag)) {
+                html.push_str(&netscape_link(b, "        "));
+            }
+            html.push_str("    </DL><p>\n");
+        }
+    }
+    for b in bookmarks.iter().filter(|b| !folders || b.tags.is_empty()) {
+        html.push_str(&netscape_link(b, "    "));
+    }
+
+    html.push_str("</DL><p>\n");
+    html
+}
+
+fn fields(b: &Bookmark) -> Value {
+    serde_json::to_value(WithImage(b)).expect("Bookmarks always serialize")
+}
+
+/// The bookmarks as a JSON array
+pub fn json(bookmarks: &[Bookmark]) -> String {
+    let all: Vec<WithImage> = bookmarks.iter().map(WithImage).collect();
+    serde_json::to_string_pretty(&all).expect("Bookmarks always serialize")
+}
+
+/// One JSON object per line
+pub fn json_lines(bookmarks: &[Bookmark]) -> String {
+    bookmarks.iter()
+        .map(|b| serde_json::to_string(&WithImage(b)).expect("Bookmarks always serialize") + "\n")
+        .collect()
+}
+
+/// A field as plain text: lists are joined with commas and missing values
+/// are empty
+fn field_text(fields: &Value, name: &str) -> String {
+    match fields[name] {
+        Value::Null => String::new(),
+        Value::String(ref s) => s.clone(),
+        Value::Array(ref items) => items.iter()
+            .map(|item| item.as_str().map(String::from).unwrap_or_else(|| item.to_string()))
+            .collect::<Vec<String>>()
+            .join(","),
+        ref other => other.to_string()
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Tab separated values can't be quoted, so tabs and line breaks become spaces
+fn tsv_field(text: &str) -> String {
+    text.replace(&['\t', '\n', '\r'][..], " ")
+}
+
+fn delimited(bookmarks: &[Bookmark], separator: &str, field: fn(&str) -> String) -> String {
+    let mut text = COLUMNS.join(separator);
+    text.push('\n');
+    for b in bookmarks {
+        let fields = fields(b);
+        let row: Vec<String> = COLUMNS.iter().map(|name| field(&field_text(&fields, name))).collect();
+        text.push_str(&row.join(separator));
+        text.push('\n');
+    }
+    text
+}
+
+/// Comma separated values with a header row, quoted where needed
+pub fn csv(bookmarks: &[Bookmark]) -> String {
+    delimited(bookmarks, ",", csv_field)
+}
+
+/// Tab separated values with a header row
+pub fn tsv(bookmarks: &[Bookmark]) -> String {
+    delimited(bookmarks, "\t", tsv_field)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(String),
+}
+
+/// A line per bookmark, such as `{label}\t{url}`. Any of `FIELDS` can go in
+/// braces, and `\t`, `\n`, `\{` and `\\` are escapes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => text.push('\t'),
+                    Some('n') => text.push('\n'),
+                    Some(other) => text.push(other),
+                    None => text.push('\\')
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(Error::Template(format!("Unclosed {{{}", name)))
+                        }
+                    }
+                    if !FIELDS.contains(&name.as_str()) {
+                        return Err(Error::Template(format!("Unknown field {{{}}}, expected one of {}",
+                                                           name, FIELDS.join(", "))));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(name));
+                },
+                c => text.push(c)
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template{parts})
+    }
+
+    pub fn render(&self, b: &Bookmark) -> String {
+        let fields = fields(b);
+        self.parts.iter().map(|part| match *part {
+            Part::Text(ref text) => text.clone(),
+            Part::Field(ref name) => field_text(&fields, name)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import;
+
+    fn bookmarks() -> Vec<Bookmark> {
+        vec![
+            Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://example.com/?a=1&b=2|Tom & Jerry|rust,web")).unwrap(),
+            Bookmark::new_from_line(String::from("b456|2018-01-02T00:00:00+01:00|:6|https://example.org/|Untagged|")).unwrap(),
+            Bookmark::new_from_line(String::from("c789|2018-01-03T00:00:00Z|:7|https://example.net/|Web|web")).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn netscape_test() {
+        let html = netscape(&bookmarks(), false);
+        assert!(html.contains("<DT><A HREF=\"https://example.com/?a=1&amp;b=2\" ADD_DATE=\"1513597589\" TAGS=\"rust,web\">Tom &amp; Jerry</A>"));
+        assert!(html.contains("ADD_DATE=\"1514847600\">Untagged</A>"));
+        assert!(!html.contains("<H3>"));
+
+        let folders = netscape(&bookmarks(), true);
+        assert!(folders.contains("<DT><H3>rust</H3>"));
+        assert!(folders.contains("<DT><H3>web</H3>"));
+    }
+
+    #[test]
+    fn json_test() {
+        let b = &bookmarks()[0];
+        assert!(serde_json::to_value(b).unwrap().get("image").is_none());
+        let value = fields(b);
+        assert_eq!(FIELDS.len(), value.as_object().unwrap().len());
+        for field in FIELDS {
+            assert!(value.get(field).is_some(), "{} missing", field);
+        }
+        assert_eq!("2017-12-18T11:46:29Z", value["created_at"]);
+        assert_eq!(serde_json::json!(["rust", "web"]), value["tags"]);
+        assert_eq!(Value::Null, value["last_visited"]);
+
+        assert_eq!(3, json_lines(&bookmarks()).lines().count());
+        let all: Value = serde_json::from_str(&json(&bookmarks())).unwrap();
+        assert_eq!(3, all.as_array().unwrap().len());
+    }
+
+    #[test]
+    fn delimited_test() {
+        let csv = csv(&bookmarks());
+        let mut lines = csv.lines();
+        assert_eq!(Some("label,title,url,tags,created_at,last_visited,visit_count,image"), lines.next());
+        assert_eq!(Some("5,Tom & Jerry,https://example.com/?a=1&b=2,\"rust,web\",2017-12-18T11:46:29Z,,0,"), lines.next());
+        assert_eq!("a b c", tsv_field("a\tb\nc"));
+        assert_eq!("\"say \"\"hi\"\"\"", csv_field("say \"hi\""));
+        assert!(tsv(&bookmarks()).starts_with("label\ttitle\t"));
+    }
+
+    #[test]
+    fn template_test() {
+        let b = &bookmarks()[0];
+        assert_eq!(":5\thttps://example.com/?a=1&b=2", Template::parse(":{label}\\t{url}").unwrap().render(b));
+        assert_eq!("rust,web {x}", Template::parse("{tags} \\{x}").unwrap().render(b));
+        assert!(Template::parse("{nope}").is_err());
+        assert!(Template::parse("{label").is_err());
+    }
+
+    #[test]
+    fn netscape_round_trip_test() {
+        for folders in &[false, true] {
+            let export = import::netscape::parse(&netscape(&bookmarks(), *folders));
+            assert!(export.rejected.is_empty());
+            let mut entries = export.entries;
+            entries.sort_by(|a, b| a.url.cmp(&b.url));
+            let mut original = bookmarks();
+            original.sort_by(|a, b| a.url.cmp(&b.url));
+            for (entry, b) in entries.iter().zip(original.iter()) {
+                assert_eq!(b.url, entry.url);
+                assert_eq!(b.title, entry.title);
+                assert_eq!(b.tags(), &entry.tags[..]);
+                assert_eq!(Some(timestamp(&b.created_at)), entry.created_at);
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Safe writes for files other rbm runs may be reading or writing.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use {Error, Result};
+
+/// How long to wait for another rbm to finish before giving up
+pub(crate) const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+static TEMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so
+/// readers see the old contents or the new, never a partial file. A symlink
+/// is followed, so the file it points to is replaced rather than the link,
+/// and the file keeps its permissions.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<()> {
+    let path = match fs::symlink_metadata(path.as_ref()) {
+        Ok(ref m) if m.file_type().is_symlink() => fs::canonicalize(path.as_ref())?,
+        _ => path.as_ref().to_path_buf()
+    };
+    let permissions = fs::metadata(&path).ok().map(|m| m.permissions());
+    let count = TEMP_COUNT.fetch_add(1, Ordering::SeqCst);
+    let temp = with_suffix(&path, &format!(".{}-{}.tmp", process::id(), count));
+
+    let written = File::create(&temp).and_then(|mut f| {
+        f.write_all(contents)?;
+        if let Some(permissions) = permissions {
+            f.set_permissions(permissions)?;
+        }
+        f.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp, &path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// 32 hex digits from `/dev/urandom`, for names and keys
+pub fn random_hex() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// A new directory in the system temp directory that only the current user
+/// can open, removed with its contents when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Result<TempDir> {
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        loop {
+            let path = env::temp_dir().join(format!("{}-{}-{}", prefix, process::id(), random_hex()?));
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir{path}),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An exclusive advisory lock on `<path>.lock`, held until dropped
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Waits up to `LOCK_TIMEOUT` for other holders, then fails with
+    /// `Error::Locked`
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Lock> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(with_suffix(path, ".lock"))?;
+
+        let start = Instant::now();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(Lock{_file: file}),
+                Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                    if start.elapsed() >= LOCK_TIMEOUT {
+                        return Err(Error::Locked(path.display().to_string()));
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                },
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn write_atomic_test() {
+        let path = env::temp_dir().join(format!("rbm-write-atomic-test-{}", process::id()));
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!("second", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_symlink_test() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let target = env::temp_dir().join(format!("rbm-write-atomic-target-{}", process::id()));
+        let link = env::temp_dir().join(format!("rbm-write-atomic-link-{}", process::id()));
+        fs::write(&target, b"first").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        symlink(&target, &link).unwrap();
+
+        write_atomic(&link, b"second").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!("second", fs::read_to_string(&target).unwrap());
+        assert_eq!(0o600, fs::metadata(&target).unwrap().permissions().mode() & 0o777);
+        fs::remove_file(&link).unwrap();
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn random_hex_test() {
+        let a = random_hex().unwrap();
+        assert_eq!(32, a.len());
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, random_hex().unwrap());
+    }
+
+    #[test]
+    fn temp_dir_test() {
+        let dir = TempDir::new("rbm-temp-dir-test").unwrap();
+        let other = TempDir::new("rbm-temp-dir-test").unwrap();
+        assert_ne!(dir.path(), other.path());
+        let path = dir.path().to_path_buf();
+        fs::write(path.join("file"), b"contents").unwrap();
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn lock_test() {
+        let path = env::temp_dir().join(format!("rbm-lock-test-{}", process::id()));
+        let lock = Lock::acquire(&path).unwrap();
+        let other = OpenOptions::new().write(true).open(with_suffix(&path, ".lock")).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        drop(lock);
+        assert!(other.try_lock_exclusive().is_ok());
+        fs::remove_file(with_suffix(&path, ".lock")).unwrap();
+    }
+}
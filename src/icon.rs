@@ -1,21 +1,20 @@
-use std::fs::File;
 
 use select::document::Document;
 use select::node::Node;
 use select::predicate::*;
 
-use std::io::Write;
-
 use reqwest::Client;
 use reqwest::get;
 use reqwest::header;
 
 use serde_json;
 use serde_json::Value;
-use reqwest;
 
 use url::Url;
 
+use rbmlib::files::write_atomic;
+use rbmlib::{Error, Metadata, Result};
+
 #[derive(Debug, Clone, PartialEq)]
 struct Icon{
     x: u16,
@@ -28,13 +27,20 @@ const DESKTOP_UA: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KH
 
 const MOBILE_UA: &str = "Mozilla/5.0 (Linux; Android 6.0; Nexus 5 Build/MRA58N) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/64.0.3282.119 Mobile Safari/537.36";
 
-fn split_x_y(sizes: &str) -> (u16, u16) {
+/// The width and height in `sizes`, or 1x1 for `any` and lists of sizes
+fn split_x_y(sizes: &str) -> Result<(u16, u16)> {
     let x_y = sizes.split('x').collect::<Vec<&str>>();
     if x_y.len() != 2 {
-        return (1, 1)
+        return Ok((1, 1))
     }
-    let x_y_parsed = x_y.iter().map(|&x| x.parse::<u16>().unwrap()).collect::<Vec<u16>>();
-    (x_y_parsed[0], x_y_parsed[1])
+    let x_y_parsed = x_y.iter().map(|&x| x.trim().parse::<u16>())
+        .collect::<::std::result::Result<Vec<u16>, _>>()
+        .map_err(|_| Error::Parse(format!("Icon size {:?}", sizes)))?;
+    Ok((x_y_parsed[0], x_y_parsed[1]))
+}
+
+fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|e| Error::Parse(format!("{}: {}", url, e)))
 }
 
 fn attr_parser(doc: &Document, _attr: &str, _val: &str, url: &str) -> Vec<Icon>{
@@ -50,27 +56,30 @@ fn attr_parser(doc: &Document, _attr: &str, _val: &str, url: &str) -> Vec<Icon>{
             continue
         }
         
-        let (x, y) = split_x_y(link.attr("sizes").unwrap_or("1x1"));
-        let path = link.attr("href").unwrap();
-        if path.is_empty() {
-            continue
+        // An unreadable size is treated like `any`
+        let (x, y) = split_x_y(link.attr("sizes").unwrap_or("1x1")).unwrap_or((1, 1));
+        if let Some(href) = url_from_paths(url, href) {
+            links.push(Icon{x, y, href, poor: false});
         }
-        links.push(Icon{x, y, href: url_from_paths(url, path), poor: false});
     };
     links
 }
 
-fn icons_from_manifest(url: &str, data: &str) -> Option<Vec<Icon>>{
+/// The icons listed in a manifest, or None if there are none or it isn't
+/// JSON. Icons without a `src` or with an unreadable size are errors.
+fn icons_from_manifest(url: &str, data: &str) -> Result<Option<Vec<Icon>>>{
     let mut icons: Vec<Icon> = Vec::new();
     match serde_json::from_str(data) {
         Ok(a) => {
             let b: Value = a;
             if let Some(x) = b["icons"].as_array() {
                 for link in x.iter() {
-                    let sizes = link["sizes"].as_str().unwrap();
-                    let (x, y) = split_x_y(sizes);
+                    let sizes = link["sizes"].as_str().unwrap_or("any");
+                    let (x, y) = split_x_y(sizes)?;
 
-                    let mut src = String::from(link["src"].as_str().unwrap());
+                    let src = link["src"].as_str()
+                        .ok_or_else(|| Error::Parse(format!("Manifest icon without a src in {}", url)))?;
+                    let mut src = String::from(src);
                     if !src.starts_with("http") {
                         let mut divider = "";
                         if !url.ends_with("/") && !&src.starts_with("/") {
@@ -83,12 +92,12 @@ fn icons_from_manifest(url: &str, data: &str) -> Option<Vec<Icon>>{
                     icons.push(Icon{x, y, href: src, poor:false});
                 };
 
-                if !icons.is_empty() { return Some(icons) };
-                return None
+                if !icons.is_empty() { return Ok(Some(icons)) };
+                return Ok(None)
             }
-            None
+            Ok(None)
         },
-        _ => None
+        _ => Ok(None)
     }
 }
 
@@ -104,11 +113,13 @@ fn get_image_paths(doc: &Document, url: &str) -> Option<Vec<Icon>>{
     
     if links.is_empty() {
         for link in doc.find(Name("meta").and(Attr("property", "og:image"))).collect::<Vec<Node>>(){
-            let path = link.attr("content").unwrap();
+            let path = link.attr("content").unwrap_or("");
             if path.is_empty() {
                 continue
             }
-            links.push(Icon{x: 1, y: 1, href: url_from_paths(url, path), poor: true});
+            if let Some(href) = url_from_paths(url, path) {
+                links.push(Icon{x: 1, y: 1, href, poor: true});
+            }
         };
     };
     
@@ -120,21 +131,22 @@ fn get_image_paths(doc: &Document, url: &str) -> Option<Vec<Icon>>{
     }
 }
 
-fn url_from_paths(root: &str, path: &str) -> String{
+/// The path as an absolute url, or None if it can't be joined to the root
+fn url_from_paths(root: &str, path: &str) -> Option<String>{
     let mut image_url = String::from(path);
     if image_url.starts_with("//") {
         image_url.insert_str(0, "http:");
-        return image_url
+        return Some(image_url)
     }
     
     if image_url.starts_with("http") {
-       return image_url
+       return Some(image_url)
    }              
     
-    return String::from(Url::parse(root).unwrap().join(path).unwrap().as_str());
+    Url::parse(root).and_then(|root| root.join(path)).ok().map(|u| u.into_string())
 }
 
-fn document_for_ua(url: &str, ua: &str) -> Result<(String, Document), reqwest::Error>{
+fn document_for_ua(url: &str, ua: &str) -> Result<(String, Document)>{
     let mut headers = header::HeaderMap::new();
     headers.insert(header::USER_AGENT, header::HeaderValue::from_str(&ua.to_string()).unwrap());
 
@@ -152,13 +164,13 @@ fn document_for_ua(url: &str, ua: &str) -> Result<(String, Document), reqwest::E
 }
 
 // TODO: Change to Option
-fn get_manifest_json(url: &str, ua: &str) -> Result<String, reqwest::Error>{
+fn get_manifest_json(url: &str, ua: &str) -> Result<String>{
     let mut headers = header::HeaderMap::new();
     headers.insert(header::USER_AGENT, header::HeaderValue::from_str(&ua.to_string()).unwrap());
 
-    let mut root_url = Url::parse(url).unwrap();
+    let mut root_url = parse_url(url)?;
     root_url.set_path(""); 
-    let parsed = root_url.join("manifest.json").unwrap();
+    let parsed = root_url.join("manifest.json").map_err(|e| Error::Parse(format!("{}: {}", url, e)))?;
     // get a client builder
     let client = Client::builder()
         .default_headers(headers)
@@ -176,10 +188,15 @@ fn get_best_icon(all_icons: &mut Vec<Icon>, new_icons: &Vec<Icon>) -> Icon {
     return all_icons.last().unwrap().to_owned();
 }
 
-fn get_page_header_icons(url: &str) -> Result<Option<Icon>, reqwest::Error>{
+/// The best icon in the page's head, trying the mobile page if the desktop
+/// one, already fetched or not, has no large icon
+fn get_page_header_icons(url: &str, desktop: Option<(String, Document)>) -> Result<Option<Icon>>{
     let mut all_icons: Vec<Icon> = Vec::new();
 
-    let (final_url, document) = try!(document_for_ua(url, DESKTOP_UA));
+    let (final_url, document) = match desktop {
+        Some(fetched) => fetched,
+        None => try!(document_for_ua(url, DESKTOP_UA))
+    };
     let best_desktop_icon = get_image_paths(&document, &final_url)
         .map(|i| get_best_icon(&mut all_icons, &i));
 
@@ -193,34 +210,142 @@ fn get_page_header_icons(url: &str) -> Result<Option<Icon>, reqwest::Error>{
               .map(|i| get_best_icon(&mut all_icons, &i)));
 }
 
-fn get_icon_objects(url: &str) -> Result<Option<Icon>, reqwest::Error>{
-    let manifest_test = get_manifest_json(url, DESKTOP_UA);
+/// The best icon in the manifest, if it lists any it can use
+fn manifest_icon(url: &str, data: &str) -> Option<Icon> {
+    icons_from_manifest(url, data).ok().and_then(|icons| icons).map(|i| {
+        let mut all_icons: Vec<Icon> = Vec::new();
+        get_best_icon(&mut all_icons, &i)
+    })
+}
 
-    let icon = match manifest_test {
-        Ok(data) => {
-            icons_from_manifest(&url, &data).map(|i| {
-                let mut all_icons: Vec<Icon> = Vec::new();
-                get_best_icon(&mut all_icons, &i)
-            })
-        },
-        _ => None
-    };
+fn get_icon_objects(url: &str) -> Result<Option<Icon>>{
+    let icon = get_manifest_json(url, DESKTOP_UA).ok()
+        .and_then(|data| manifest_icon(url, &data));
 
     match icon {
         Some(_) => Ok(icon),
-        None => get_page_header_icons(url)
+        None => get_page_header_icons(url, None)
+    }
+}
+
+/// What `add` learns from fetching the page
+#[derive(Debug, Default)]
+pub struct PageInfo {
+    pub title: Option<String>,
+    pub metadata: Metadata,
+    /// Where the page's icon is, found from the same fetch
+    pub icon: Option<String>,
+}
+
+const FEED_TYPES: [&str; 3] = ["application/rss+xml", "application/atom+xml", "application/feed+json"];
+
+/// Collapses the whitespace page text tends to be wrapped in
+fn clean_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn title_from_document(doc: &Document) -> Option<String> {
+    let title = doc.find(Name("title")).next().map(|n| n.text());
+    let og_title = doc.find(Name("meta").and(Attr("property", "og:title"))).next()
+        .and_then(|n| n.attr("content").map(String::from));
+    title.into_iter().chain(og_title)
+        .map(|t| clean_text(&t))
+        .find(|t| !t.is_empty())
+}
+
+fn title_from_manifest(data: &str) -> Option<String> {
+    let manifest: Value = serde_json::from_str(data).ok()?;
+    ["name", "short_name"].iter()
+        .filter_map(|key| manifest[*key].as_str())
+        .map(clean_text)
+        .find(|t| !t.is_empty())
+}
+
+/// The content of the first matching, non-empty `<meta>`
+fn meta_content(doc: &Document, attr: &str, value: &str) -> Option<String> {
+    doc.find(Name("meta").and(Attr(attr, value)))
+        .filter_map(|n| n.attr("content").map(clean_text))
+        .find(|content| !content.is_empty())
+}
+
+fn metadata_from_document(doc: &Document, url: &str) -> Metadata {
+    let description = meta_content(doc, "name", "description")
+        .or_else(|| meta_content(doc, "property", "og:description"));
+    let canonical = doc.find(Name("link").and(Attr("rel", "canonical"))).next()
+        .and_then(|n| n.attr("href"))
+        .filter(|href| !href.is_empty())
+        .and_then(|href| url_from_paths(url, href));
+    let lang = doc.find(Name("html")).next().and_then(|n| n.attr("lang"));
+
+    let mut feeds: Vec<String> = Vec::new();
+    for link in doc.find(Name("link").and(Attr("rel", "alternate"))) {
+        let is_feed = link.attr("type").map(|t| FEED_TYPES.contains(&t.trim())) == Some(true);
+        if let (true, Some(href)) = (is_feed, link.attr("href")) {
+            let feed = match url_from_paths(url, href) {
+                Some(feed) => feed,
+                None => continue
+            };
+            if !href.is_empty() && !feeds.contains(&feed) {
+                feeds.push(feed);
+            }
+        }
+    }
+
+    Metadata{
+        description: description.unwrap_or_default(),
+        site_name: meta_content(doc, "property", "og:site_name").unwrap_or_default(),
+        canonical: canonical.unwrap_or_default(),
+        lang: lang.map(|l| l.trim().to_string()).unwrap_or_default(),
+        feeds,
+    }
+}
+
+/// Fetches the page and its manifest once for the title, metadata and icon.
+/// The title is the page's `<title>` or `og:title`, or failing that the name
+/// in its manifest
+pub fn fetch_page(url: &str) -> PageInfo {
+    let mut page = PageInfo::default();
+    if Url::parse(url).is_err() {
+        return page
+    }
+    let manifest = get_manifest_json(url, DESKTOP_UA).ok();
+    let desktop = document_for_ua(url, DESKTOP_UA).ok();
+    if let Some((ref final_url, ref document)) = desktop {
+        page.title = title_from_document(document);
+        page.metadata = metadata_from_document(document, final_url);
     }
+    if page.title.is_none() {
+        page.title = manifest.as_ref().and_then(|data| title_from_manifest(data));
+    }
+
+    let icon = match manifest.as_ref().and_then(|data| manifest_icon(url, data)) {
+        Some(icon) => Some(icon),
+        None => desktop.and_then(|fetched| get_page_header_icons(url, Some(fetched)).ok()).and_then(|icon| icon)
+    };
+    page.icon = icon.map(|i| i.href);
+    page
 }
 
+/// The hostname, for pages without a title
+pub fn host_title(url: &str) -> String {
+    Url::parse(url).ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_else(|| url.to_string())
+}
 
-pub fn download_image(url: &str, fs_path: &str) -> Result<(), reqwest::Error>{
+pub fn download_image(url: &str, fs_path: &str) -> Result<()>{
+    parse_url(url)?;
     let icon_url = match get_icon_objects(url){
         Ok(links) => links.map(|i| i.href.clone()),
         _ => None};
+    save_icon(icon_url.as_deref(), url, fs_path)
+}
 
+/// Downloads an icon already found for the url
+pub fn save_icon(icon_url: Option<&str>, url: &str, fs_path: &str) -> Result<()>{
     match icon_url {
         Some(image_url) => {
-            download_media(&image_url, fs_path)
+            download_media(image_url, fs_path)
         },
         _  => Ok(println!("No image for {}", url))
     }
@@ -233,14 +358,12 @@ fn replace_extension(source: &str, dest: &str) -> String {
     return dest.to_owned();
 }
 
-pub fn download_media(url: &str, fs_path: &str) -> Result<(), reqwest::Error>{
+pub fn download_media(url: &str, fs_path: &str) -> Result<()>{
     let mut resp = get(url)?;
     let mut buf: Vec<u8> = vec![];
-    resp.copy_to(&mut buf).expect("Bad body");
+    resp.copy_to(&mut buf)?;
     let amended_path = replace_extension(url, fs_path);
-    let mut f = File::create(amended_path).unwrap();
-    f.write_all(buf.as_slice()).unwrap();
-    Ok(())
+    write_atomic(amended_path, &buf)
 }
 
 #[cfg(test)]
@@ -249,8 +372,9 @@ mod tests {
 
     #[test]
     fn split_x_y_test(){
-        assert_eq!((1, 2), split_x_y("1x2"));
-        assert_eq!((1, 1), split_x_y("any"));
+        assert_eq!((1, 2), split_x_y("1x2").unwrap());
+        assert_eq!((1, 1), split_x_y("any").unwrap());
+        assert!(split_x_y("AxB").is_err());
     }
 
     #[test]
@@ -258,7 +382,11 @@ mod tests {
         assert_eq!(vec![
             Icon{x: 114, y: 114, href: String::from("https://assets-cdn.github.com/apple-touch-icon-114x114.png"), poor: false},
             Icon{x: 120, y: 120, href: String::from("https://assets-cdn.github.com/apple-touch-icon-120x120.png"), poor: false}],
-                   icons_from_manifest("http://www.example.com", "{\"name\":\"GitHub\",\"icons\":[{\"sizes\":\"114x114\",\"src\":\"https://assets-cdn.github.com/apple-touch-icon-114x114.png\"},{\"sizes\":\"120x120\",\"src\":\"https://assets-cdn.github.com/apple-touch-icon-120x120.png\"}]}").unwrap())}
+                   icons_from_manifest("http://www.example.com", "{\"name\":\"GitHub\",\"icons\":[{\"sizes\":\"114x114\",\"src\":\"https://assets-cdn.github.com/apple-touch-icon-114x114.png\"},{\"sizes\":\"120x120\",\"src\":\"https://assets-cdn.github.com/apple-touch-icon-120x120.png\"}]}").unwrap().unwrap());
+        assert_eq!(None, icons_from_manifest("http://www.example.com", "<html>Not found</html>").unwrap());
+        assert!(icons_from_manifest("http://www.example.com", "{\"icons\":[{\"sizes\":\"AxB\",\"src\":\"/a.png\"}]}").is_err());
+        assert!(icons_from_manifest("http://www.example.com", "{\"icons\":[{\"sizes\":\"48x48\"}]}").is_err());
+    }
 
     #[test]
     fn attr_parser_test(){
@@ -273,12 +401,59 @@ mod tests {
 
     #[test]
     fn url_from_paths_test(){
-        assert_eq!("https://www.example.com/123",
+        assert_eq!(Some(String::from("https://www.example.com/123")),
                    url_from_paths("https://www.example.com", "123"));
-        assert_eq!("http://www.example2.com/123",
+        assert_eq!(Some(String::from("http://www.example2.com/123")),
                    url_from_paths("https://www.example.com", "http://www.example2.com/123"));
-        assert_eq!("http://www.example2.com/123",
+        assert_eq!(Some(String::from("http://www.example2.com/123")),
                    url_from_paths("https://www.example.com", "//www.example2.com/123"));
+        assert_eq!(None, url_from_paths("https://www.example.com", "ftp://["));
+        assert_eq!(None, url_from_paths("not a url", "123"));
+    }
+
+    #[test]
+    fn title_test() {
+        let doc = Document::from("<html><head><title>\n  Example\n  Domain </title><meta property=\"og:title\" content=\"Og\"/></head></html>");
+        assert_eq!(Some(String::from("Example Domain")), title_from_document(&doc));
+        let doc = Document::from("<html><head><title> </title><meta property=\"og:title\" content=\"Og\"/></head></html>");
+        assert_eq!(Some(String::from("Og")), title_from_document(&doc));
+        assert_eq!(None, title_from_document(&Document::from("<html></html>")));
+
+        assert_eq!(Some(String::from("GH")), title_from_manifest("{\"short_name\":\"GH\",\"icons\":[]}"));
+        assert_eq!(None, title_from_manifest("not json"));
+
+        assert_eq!("www.example.com", host_title("https://www.example.com/page"));
+        assert_eq!("not a url", host_title("not a url"));
+    }
+
+    #[test]
+    fn download_image_test() {
+        assert!(download_image("example.com", "unused.png").is_err());
+        assert!(get_manifest_json("example.com", DESKTOP_UA).is_err());
+    }
+
+    #[test]
+    fn metadata_test() {
+        let doc = Document::from("<html lang=\"en-GB\"><head>\
+            <meta property=\"og:description\" content=\"Og description\"/>\
+            <meta name=\"description\" content=\" A  page \"/>\
+            <meta property=\"og:site_name\" content=\"Example\"/>\
+            <link rel=\"canonical\" href=\"/page\"/>\
+            <link rel=\"alternate\" type=\"application/rss+xml\" href=\"/rss\"/>\
+            <link rel=\"alternate\" type=\"application/atom+xml\" href=\"https://feeds.example.com/atom\"/>\
+            <link rel=\"alternate\" hreflang=\"fr\" href=\"/fr/\"/>\
+            </head></html>");
+        let metadata = metadata_from_document(&doc, "https://www.example.com/page?ref=1");
+
+        assert_eq!("A page", metadata.description);
+        assert_eq!("Example", metadata.site_name);
+        assert_eq!("https://www.example.com/page", metadata.canonical);
+        assert_eq!("en-GB", metadata.lang);
+        assert_eq!(vec!["https://www.example.com/rss", "https://feeds.example.com/atom"], metadata.feeds);
+        assert!(metadata_from_document(&Document::from("<html></html>"), "https://www.example.com/").is_empty());
+        let broken = Document::from("<html><head><link rel=\"canonical\" href=\"ftp://[\"/>\
+            <link rel=\"alternate\" type=\"application/rss+xml\" href=\"ftp://[\"/></head></html>");
+        assert!(metadata_from_document(&broken, "https://www.example.com/").is_empty());
     }
 
     #[test]
@@ -0,0 +1,124 @@
+//! The `Bookmarks` file in a Chrome or Chromium profile directory:
+//!
+//! ```json
+//! {"roots": {"bookmark_bar": {"type": "folder", "name": "Bookmarks bar", "children": [
+//!     {"type": "folder", "name": "Reading", "children": [
+//!         {"type": "url", "name": "Example", "url": "https://example.com/", "date_added": "13188727189000000"}
+//!     ]}
+//! ]}}}
+//! ```
+//!
+//! The folders a bookmark is in become its tags. The roots themselves, the
+//! bookmarks bar and other bookmarks, don't.
+
+use serde_json::{self, Value};
+use time::Timespec;
+
+use import::{Entry, Export};
+use {normalize_tag, Error, Result};
+
+/// Seconds from 1601-01-01, where Chrome counts from, to 1970-01-01
+const WEBKIT_EPOCH_OFFSET: i64 = 11_644_473_600;
+
+pub fn parse(json: &str) -> Result<Export> {
+    let value: Value = serde_json::from_str(json)
+        .map_err(|e| Error::Parse(format!("Not a Chrome bookmarks file: {}", e)))?;
+    let roots = value.get("roots").and_then(Value::as_object)
+        .ok_or_else(|| Error::Parse(String::from("Not a Chrome bookmarks file: no roots")))?;
+
+    let mut export = Export::default();
+    for root in roots.values() {
+        read_children(root, &mut Vec::new(), &mut export);
+    }
+    Ok(export)
+}
+
+fn read_children(folder: &Value, folders: &mut Vec<String>, export: &mut Export) {
+    let children = match folder.get("children").and_then(Value::as_array) {
+        Some(children) => children,
+        None => return
+    };
+    for node in children {
+        let name = node.get("name").and_then(Value::as_str).unwrap_or("");
+        match node.get("type").and_then(Value::as_str) {
+            Some("folder") => {
+                let tag = normalize_tag(name);
+                let named = !tag.is_empty();
+                if named {
+                    folders.push(tag);
+                }
+                read_children(node, folders, export);
+                if named {
+                    folders.pop();
+                }
+            },
+            Some("url") => {
+                let mut tags = Vec::new();
+                for tag in folders.iter() {
+                    if !tags.contains(tag) {
+                        tags.push(tag.clone());
+                    }
+                }
+                export.push(Entry{
+                    url: node.get("url").and_then(Value::as_str).unwrap_or("").trim().to_string(),
+                    title: name.trim().to_string(),
+                    tags,
+                    created_at: node.get("date_added").and_then(webkit_time),
+                    ..Entry::default()
+                });
+            },
+            _ => export.rejected.push(format!("Unknown kind of bookmark {:?}", name))
+        }
+    }
+}
+
+/// Chrome's microseconds since 1601, written as a string
+fn webkit_time(value: &Value) -> Option<Timespec> {
+    let micros = match *value {
+        Value::String(ref s) => s.parse::<i64>().ok()?,
+        ref v => v.as_i64()?
+    };
+    let seconds = micros / 1_000_000 - WEBKIT_EPOCH_OFFSET;
+    if micros <= 0 || seconds <= 0 {
+        return None;
+    }
+    Some(Timespec::new(seconds, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOKMARKS: &str = r#"{
+        "checksum": "0",
+        "roots": {
+            "bookmark_bar": {"type": "folder", "name": "Bookmarks bar", "children": [
+                {"type": "url", "name": "Rust", "url": "https://www.rust-lang.org/", "date_added": "13188727189000000"},
+                {"type": "folder", "name": "Web Dev", "children": [
+                    {"type": "folder", "name": "Docs", "children": [
+                        {"type": "url", "name": "MDN", "url": "https://developer.mozilla.org/", "date_added": "0"}
+                    ]},
+                    {"type": "url", "name": "Bookmarklet", "url": "javascript:void(0)"}
+                ]}
+            ]},
+            "other": {"type": "folder", "name": "Other bookmarks", "children": []},
+            "synced": {"type": "folder", "name": "Mobile bookmarks"}
+        },
+        "version": 1
+    }"#;
+
+    #[test]
+    fn parse_test() {
+        let export = parse(BOOKMARKS).unwrap();
+        assert_eq!(vec![
+            Entry{url: String::from("https://www.rust-lang.org/"), title: String::from("Rust"),
+                  tags: vec![], created_at: Some(Timespec::new(1544253589, 0)), ..Entry::default()},
+            Entry{url: String::from("https://developer.mozilla.org/"), title: String::from("MDN"),
+                  tags: vec![String::from("web-dev"), String::from("docs")], ..Entry::default()},
+        ], export.entries);
+        assert_eq!(1, export.rejected.len());
+
+        assert!(parse("[]").is_err());
+        assert!(parse("not json").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Comma separated files from anywhere else, one bookmark a row. Which
+//! column holds what is given with a spec such as `url,title,tags,date`:
+//!
+//! - `url` is required, the others may be left out
+//! - `tags` are separated by commas, so quote the field if there are several
+//! - `date` is seconds since the epoch, `2018-12-18T11:46:29Z` or `2018-12-18`
+//! - `-` skips a column
+//!
+//! A first row whose url column reads `url` is taken as a header and skipped.
+//! Fields may be quoted with `"`, doubling any quotes inside.
+
+use std::mem;
+
+use import::{iso_time, unix_time, Entry, Export};
+use {normalize_tag, Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Url,
+    Title,
+    Tags,
+    Date,
+    Skip,
+}
+
+/// Reads a column spec such as `url,title,tags,date`
+pub fn columns(spec: &str) -> Result<Vec<Column>> {
+    let columns = spec.split(',').map(|name| match name.trim() {
+        "url" => Ok(Column::Url),
+        "title" => Ok(Column::Title),
+        "tags" => Ok(Column::Tags),
+        "date" => Ok(Column::Date),
+        "-" => Ok(Column::Skip),
+        name => Err(Error::Parse(format!("Unknown column {:?}, expected url, title, tags, date or -", name)))
+    }).collect::<Result<Vec<Column>>>()?;
+    if !columns.contains(&Column::Url) {
+        return Err(Error::Parse(String::from("The columns need to include url")));
+    }
+    Ok(columns)
+}
+
+pub fn parse(text: &str, columns: &[Column]) -> Export {
+    let mut export = Export::default();
+    for (number, row) in rows(text).into_iter().enumerate() {
+        let mut entry = Entry::default();
+        for (column, field) in columns.iter().zip(row.iter()) {
+            let field = field.trim();
+            match *column {
+                Column::Url => entry.url = field.to_string(),
+                Column::Title => entry.title = field.to_string(),
+                Column::Tags => {
+                    for tag in field.split(',').map(normalize_tag) {
+                        if !tag.is_empty() && !entry.tags.contains(&tag) {
+                            entry.tags.push(tag);
+                        }
+                    }
+                },
+                Column::Date => entry.created_at = unix_time(field).or_else(|| iso_time(field)),
+                Column::Skip => {}
+            }
+        }
+        if number == 0 && entry.url.eq_ignore_ascii_case("url") {
+            continue;
+        }
+        export.push(entry);
+    }
+    export
+}
+
+/// Splits the text into rows of fields, leaving out blank lines
+fn rows(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            },
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => row.push(mem::take(&mut field)),
+            '\r' if !quoted => {},
+            '\n' if !quoted => {
+                row.push(mem::take(&mut field));
+                let row = mem::take(&mut row);
+                if row.iter().any(|f| !f.is_empty()) {
+                    rows.push(row);
+                }
+            },
+            c => field.push(c)
+        }
+    }
+    row.push(field);
+    if row.iter().any(|f| !f.is_empty()) {
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+
+    #[test]
+    fn columns_test() {
+        assert_eq!(vec![Column::Title, Column::Skip, Column::Url], columns("title, -,url").unwrap());
+        assert!(columns("title,date").is_err());
+        assert!(columns("url,notes").is_err());
+    }
+
+    #[test]
+    fn rows_test() {
+        assert_eq!(vec![vec!["a", "b, \"c\""], vec!["", "d\ne"]],
+                   rows("a,\"b, \"\"c\"\"\"\r\n\n,\"d\ne\"\n"));
+    }
+
+    #[test]
+    fn parse_test() {
+        let text = "URL,Title,Tags,Added\n\
+                    https://www.rust-lang.org/,Rust,\"rust, Web Dev\",1545133589\n\
+                    https://example.com/,Example,,2018-12-18\n\
+                    ,No link,,\n";
+        let export = parse(text, &columns("url,title,tags,date").unwrap());
+        assert_eq!(vec![
+            Entry{url: String::from("https://www.rust-lang.org/"), title: String::from("Rust"),
+                  tags: vec![String::from("rust"), String::from("web-dev")],
+                  created_at: Some(Timespec::new(1545133589, 0)), ..Entry::default()},
+            Entry{url: String::from("https://example.com/"), title: String::from("Example"),
+                  created_at: Some(Timespec::new(1545091200, 0)), ..Entry::default()},
+        ], export.entries);
+        assert_eq!(1, export.rejected.len());
+
+        let export = parse("Example,https://example.com/\n", &columns("title,url").unwrap());
+        assert_eq!("https://example.com/", export.entries[0].url);
+    }
+}
@@ -0,0 +1,203 @@
+//! Bookmarks in a Firefox profile's `places.sqlite`. Needs the `sqlite`
+//! feature.
+//!
+//! Bookmarks are rows in `moz_bookmarks` pointing at the page in
+//! `moz_places`. Tags are stored as folders under the tags root holding a
+//! second row for each page; they become tags rather than folders here. The
+//! folders a bookmark is filed in become tags too, as in the other formats.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use rusqlite::{Connection, NO_PARAMS};
+
+use files::{with_suffix, TempDir};
+use import::{epoch_time, Entry, Export};
+use {normalize_tag, Result};
+
+const BOOKMARK: i64 = 1;
+const FOLDER: i64 = 2;
+const TAGS_ROOT: &str = "tags________";
+
+struct Item {
+    id: i64,
+    kind: i64,
+    parent: i64,
+    title: String,
+    guid: String,
+    date_added: i64,
+    place: Option<i64>,
+}
+
+struct Place {
+    url: String,
+    title: String,
+    visit_count: i64,
+    last_visit_date: Option<i64>,
+}
+
+/// Reads a copy of the database in a private temporary directory, since
+/// Firefox keeps the original locked while it runs
+pub fn parse<P: AsRef<Path>>(path: P) -> Result<Export> {
+    let dir = TempDir::new("rbm-places")?;
+    let copy = dir.path().join("places.sqlite");
+    copy_database(path.as_ref(), &copy)?;
+    let conn = Connection::open(&copy)?;
+    read(&conn)
+}
+
+/// Copies the database with its write ahead log, which holds the latest
+/// changes until Firefox checkpoints it
+fn copy_database(path: &Path, copy: &Path) -> Result<()> {
+    fs::copy(path, copy)?;
+    let wal = with_suffix(path, "-wal");
+    if wal.exists() {
+        fs::copy(wal, with_suffix(copy, "-wal"))?;
+    }
+    Ok(())
+}
+
+fn read(conn: &Connection) -> Result<Export> {
+    let mut items: Vec<Item> = Vec::new();
+    let mut stmt = conn.prepare(
+        "SELECT id, type, parent, IFNULL(title, ''), IFNULL(guid, ''), IFNULL(dateAdded, 0), fk
+         FROM moz_bookmarks ORDER BY id")?;
+    for row in stmt.query_map(NO_PARAMS, |row| {
+        Ok(Item{id: row.get(0)?, kind: row.get(1)?, parent: row.get(2)?, title: row.get(3)?, guid: row.get(4)?,
+                date_added: row.get(5)?, place: row.get(6)?})
+    })? {
+        items.push(row?);
+    }
+
+    let mut places: HashMap<i64, Place> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "SELECT id, url, IFNULL(title, ''), IFNULL(visit_count, 0), last_visit_date FROM moz_places
+         WHERE id IN (SELECT fk FROM moz_bookmarks)")?;
+    for row in stmt.query_map(NO_PARAMS, |row| {
+        Ok((row.get(0)?, Place{url: row.get(1)?, title: row.get(2)?, visit_count: row.get(3)?,
+                               last_visit_date: row.get(4)?}))
+    })? {
+        let (id, place) = row?;
+        places.insert(id, place);
+    }
+
+    Ok(entries(&items, &places))
+}
+
+fn entries(items: &[Item], places: &HashMap<i64, Place>) -> Export {
+    let by_id: HashMap<i64, &Item> = items.iter().map(|item| (item.id, item)).collect();
+    let tags_root = items.iter().find(|item| item.guid == TAGS_ROOT).map(|item| item.id);
+    let is_tag_folder = |id: i64| match (by_id.get(&id), tags_root) {
+        (Some(folder), Some(root)) => folder.parent == root,
+        _ => false
+    };
+
+    let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+    for item in items.iter().filter(|item| item.kind == BOOKMARK && is_tag_folder(item.parent)) {
+        if let (Some(place), Some(folder)) = (item.place, by_id.get(&item.parent)) {
+            tags.entry(place).or_default().push(normalize_tag(&folder.title));
+        }
+    }
+
+    let mut export = Export::default();
+    for item in items.iter().filter(|item| item.kind == BOOKMARK && !is_tag_folder(item.parent)) {
+        let place = match item.place.and_then(|id| places.get(&id)) {
+            Some(place) => place,
+            None => {
+                export.rejected.push(format!("No page for {:?}", item.title));
+                continue;
+            }
+        };
+
+        let mut entry_tags = folders(item, &by_id);
+        for tag in item.place.and_then(|id| tags.get(&id)).into_iter().flatten() {
+            if !tag.is_empty() && !entry_tags.contains(tag) {
+                entry_tags.push(tag.clone());
+            }
+        }
+        export.push(Entry{
+            url: place.url.clone(),
+            title: if item.title.is_empty() { place.title.clone() } else { item.title.clone() },
+            tags: entry_tags,
+            created_at: epoch_time(item.date_added),
+            visit_count: place.visit_count.max(0) as u32,
+            last_visited: place.last_visit_date.and_then(epoch_time),
+        });
+    }
+    export
+}
+
+/// Tags for the folders above the item, leaving out the menu, toolbar and
+/// other roots
+fn folders(item: &Item, by_id: &HashMap<i64, &Item>) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut parent = by_id.get(&item.parent);
+    while let Some(folder) = parent {
+        // The roots sit directly under the unnamed root, which has no parent
+        let above = match by_id.get(&folder.parent) {
+            Some(above) if folder.kind == FOLDER && by_id.contains_key(&above.parent) => above,
+            _ => break
+        };
+        let tag = normalize_tag(&folder.title);
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.insert(0, tag);
+        }
+        parent = Some(above);
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use time::Timespec;
+
+    const PLACES: &str = "
+        CREATE TABLE moz_places (id INTEGER PRIMARY KEY, url LONGVARCHAR, title LONGVARCHAR,
+                                 visit_count INTEGER DEFAULT 0, last_visit_date INTEGER);
+        CREATE TABLE moz_bookmarks (id INTEGER PRIMARY KEY, type INTEGER, fk INTEGER DEFAULT NULL,
+                                    parent INTEGER, position INTEGER, title LONGVARCHAR,
+                                    dateAdded INTEGER, guid TEXT);
+        INSERT INTO moz_places VALUES (1, 'https://www.rust-lang.org/', 'Rust Programming Language', 12, 1545133589000000);
+        INSERT INTO moz_places VALUES (2, 'https://developer.mozilla.org/', 'MDN', 0, NULL);
+        INSERT INTO moz_places VALUES (3, 'place:sort=8', NULL, 0, NULL);
+        INSERT INTO moz_bookmarks VALUES (1, 2, NULL, 0, 0, '', 0, 'root________');
+        INSERT INTO moz_bookmarks VALUES (2, 2, NULL, 1, 0, 'menu', 0, 'menu________');
+        INSERT INTO moz_bookmarks VALUES (3, 2, NULL, 1, 1, 'toolbar', 0, 'toolbar_____');
+        INSERT INTO moz_bookmarks VALUES (4, 2, NULL, 1, 2, 'tags', 0, 'tags________');
+        INSERT INTO moz_bookmarks VALUES (5, 1, 1, 3, 0, 'Rust', 1544253589000000, 'a');
+        INSERT INTO moz_bookmarks VALUES (6, 2, NULL, 2, 0, 'Web Dev', 0, 'b');
+        INSERT INTO moz_bookmarks VALUES (7, 1, 2, 6, 0, NULL, 0, 'c');
+        INSERT INTO moz_bookmarks VALUES (8, 2, NULL, 4, 0, 'docs', 0, 'd');
+        INSERT INTO moz_bookmarks VALUES (9, 1, 2, 8, 0, NULL, 0, 'e');
+        INSERT INTO moz_bookmarks VALUES (10, 1, 3, 2, 1, 'Recent Tags', 0, 'f');
+        INSERT INTO moz_bookmarks VALUES (11, 1, 99, 2, 2, 'Gone', 0, 'g');
+    ";
+
+    #[test]
+    fn read_test() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(PLACES).unwrap();
+        let export = read(&conn).unwrap();
+        assert_eq!(vec![
+            Entry{url: String::from("https://www.rust-lang.org/"), title: String::from("Rust"), tags: vec![],
+                  created_at: Some(Timespec::new(1544253589, 0)), visit_count: 12,
+                  last_visited: Some(Timespec::new(1545133589, 0))},
+            Entry{url: String::from("https://developer.mozilla.org/"), title: String::from("MDN"),
+                  tags: vec![String::from("web-dev"), String::from("docs")], ..Entry::default()},
+        ], export.entries);
+        assert_eq!(2, export.rejected.len());
+    }
+
+    #[test]
+    fn parse_copy_test() {
+        let path = env::temp_dir().join(format!("rbm-places-test-{}.sqlite", process::id()));
+        Connection::open(&path).unwrap().execute_batch(PLACES).unwrap();
+        assert_eq!(2, parse(&path).unwrap().entries.len());
+        fs::remove_file(&path).unwrap();
+        assert!(parse(&path).is_err());
+    }
+}
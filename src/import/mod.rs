@@ -0,0 +1,117 @@
+//! Reading bookmarks exported by browsers and other bookmark services. Each
+//! format's parser turns its file into `Entry`s, which rbm then adds the way
+//! `add` does.
+
+use time::{self, Timespec};
+use url::Url;
+
+use {timestamp, ISO_TIME_DATE};
+
+pub mod chrome;
+pub mod csv;
+#[cfg(feature = "sqlite")]
+pub mod firefox;
+pub mod netscape;
+pub mod pinboard;
+pub mod pocket;
+
+/// A bookmark read from an export, before it is hashed and labelled
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Entry {
+    pub url: String,
+    pub title: String,
+    pub tags: Vec<String>,
+    /// When it was bookmarked, if the export says
+    pub created_at: Option<Timespec>,
+    /// Visits the browser counted, for exports that keep history
+    pub visit_count: u32,
+    pub last_visited: Option<Timespec>,
+}
+
+/// Everything read from an export
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Export {
+    pub entries: Vec<Entry>,
+    /// Why each entry that couldn't be used was left out
+    pub rejected: Vec<String>,
+}
+
+/// Schemes that only mean something inside the browser that saved them
+const BROWSER_SCHEMES: &[&str] = &["place:", "javascript:", "data:", "about:", "chrome:"];
+
+impl Export {
+    /// Keeps the entry, or rejects it if it isn't an http or https url
+    pub fn push(&mut self, entry: Entry) {
+        let url = entry.url.trim().to_lowercase();
+        let web = Url::parse(&url).map(|u| u.scheme() == "http" || u.scheme() == "https").unwrap_or(false);
+        if url.is_empty() {
+            self.rejected.push(format!("No url for {:?}", entry.title));
+        } else if BROWSER_SCHEMES.iter().any(|scheme| url.starts_with(scheme)) {
+            self.rejected.push(format!("Can't open {} outside the browser", entry.url));
+        } else if !web {
+            self.rejected.push(format!("{} isn't a web link", entry.url));
+        } else {
+            self.entries.push(entry);
+        }
+    }
+}
+
+/// A time since the epoch written out as a number, see `epoch_time`
+pub fn unix_time(value: &str) -> Option<Timespec> {
+    value.trim().parse().ok().and_then(epoch_time)
+}
+
+/// A time in seconds since the epoch. Some exporters write milliseconds or
+/// microseconds instead, which are told apart by size.
+pub fn epoch_time(value: i64) -> Option<Timespec> {
+    let seconds = match value {
+        v if v <= 0 => return None,
+        v if v >= 100_000_000_000_000 => v / 1_000_000,
+        v if v >= 100_000_000_000 => v / 1000,
+        v => v
+    };
+    Some(Timespec::new(seconds, 0))
+}
+
+/// A time written as `2018-12-18T11:46:29Z`, or just the date
+pub fn iso_time(value: &str) -> Option<Timespec> {
+    let value = value.trim();
+    time::strptime(value, ISO_TIME_DATE)
+        .or_else(|_| time::strptime(value, "%Y-%m-%d"))
+        .ok()
+        .map(|tm| timestamp(&tm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_test() {
+        let mut export = Export::default();
+        export.push(Entry{url: String::from("https://example.com/"), ..Entry::default()});
+        export.push(Entry{url: String::from("place:sort=8"), ..Entry::default()});
+        export.push(Entry{title: String::from("Nowhere"), ..Entry::default()});
+        export.push(Entry{url: String::from("example.com"), ..Entry::default()});
+        export.push(Entry{url: String::from("mailto:someone@example.com"), ..Entry::default()});
+        assert_eq!(1, export.entries.len());
+        assert_eq!(4, export.rejected.len());
+    }
+
+    #[test]
+    fn unix_time_test() {
+        assert_eq!(Some(Timespec::new(1545133589, 0)), unix_time("1545133589"));
+        assert_eq!(Some(Timespec::new(1545133589, 0)), unix_time("1545133589123"));
+        assert_eq!(Some(Timespec::new(1545133589, 0)), unix_time("1545133589123456"));
+        assert_eq!(None, unix_time("0"));
+        assert_eq!(None, unix_time("yesterday"));
+    }
+
+    #[test]
+    fn iso_time_test() {
+        assert_eq!(Some(Timespec::new(1545133589, 0)), iso_time("2018-12-18T11:46:29Z"));
+        assert_eq!(Some(Timespec::new(1545133589, 0)), iso_time("2018-12-18T12:46:29+01:00"));
+        assert_eq!(Some(Timespec::new(1545091200, 0)), iso_time("2018-12-18"));
+        assert_eq!(None, iso_time("yesterday"));
+    }
+}
@@ -0,0 +1,146 @@
+//! The bookmark HTML files Firefox, Chrome and Pinboard export:
+//!
+//! ```html
+//! <DL><p>
+//!     <DT><H3 ADD_DATE="1545133589">Reading</H3>
+//!     <DL><p>
+//!         <DT><A HREF="https://example.com/" ADD_DATE="1545133589" TAGS="rust,web">Example</A>
+//!     </DL><p>
+//! </DL><p>
+//! ```
+//!
+//! Folders become tags, along with any in `TAGS`.
+
+use select::document::Document;
+use select::node::Node;
+use select::predicate::Name;
+
+use import::{unix_time, Entry, Export};
+use normalize_tag;
+
+/// Folders every browser has, which say nothing about the bookmarks in them
+const ROOT_FOLDERS: &[&str] = &["personal_toolbar_folder", "unfiled_bookmarks_folder"];
+
+pub fn parse(html: &str) -> Export {
+    let doc = Document::from(html);
+    let mut export = Export::default();
+    if let Some(list) = doc.find(Name("dl")).next() {
+        read_list(list, &mut Vec::new(), &mut export);
+    }
+    export
+}
+
+fn child<'a>(node: Node<'a>, name: &str) -> Option<Node<'a>> {
+    node.children().find(|n| n.name() == Some(name))
+}
+
+/// Reads a `<DL>`, whose `<DT>`s are links or folders. A folder's own `<DL>`
+/// is usually inside its `<DT>`, but some exporters put it straight after.
+fn read_list(list: Node, folders: &mut Vec<String>, export: &mut Export) {
+    let mut open_folder: Option<Option<String>> = None;
+    for node in list.children() {
+        match node.name() {
+            Some("dt") => {
+                open_folder = None;
+                if let Some(heading) = child(node, "h3") {
+                    let folder = folder_tag(heading);
+                    match child(node, "dl") {
+                        Some(inner) => read_folder(inner, folder, folders, export),
+                        None => open_folder = Some(folder)
+                    }
+                } else if let Some(link) = child(node, "a") {
+                    export.push(entry(link, folders));
+                }
+            },
+            Some("dl") => {
+                if let Some(folder) = open_folder.take() {
+                    read_folder(node, folder, folders, export);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+fn read_folder(list: Node, folder: Option<String>, folders: &mut Vec<String>, export: &mut Export) {
+    let named = folder.is_some();
+    folders.extend(folder);
+    read_list(list, folders, export);
+    if named {
+        folders.pop();
+    }
+}
+
+/// The tag for a folder, or none for the toolbar and other built in folders
+fn folder_tag(heading: Node) -> Option<String> {
+    if ROOT_FOLDERS.iter().any(|attr| heading.attr(attr).is_some()) {
+        return None;
+    }
+    Some(normalize_tag(&heading.text())).filter(|tag| !tag.is_empty())
+}
+
+fn entry(link: Node, folders: &[String]) -> Entry {
+    let mut tags = folders.to_vec();
+    for tag in link.attr("tags").unwrap_or("").split(',').map(normalize_tag) {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Entry{
+        url: link.attr("href").unwrap_or("").trim().to_string(),
+        title: link.text().trim().to_string(),
+        tags,
+        created_at: link.attr("add_date").and_then(unix_time),
+        ..Entry::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+
+    const EXPORT: &str = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+<DL><p>
+    <DT><H3 ADD_DATE="1545133589" PERSONAL_TOOLBAR_FOLDER="true">Bookmarks bar</H3>
+    <DL><p>
+        <DT><A HREF="https://www.rust-lang.org/" ADD_DATE="1545133589">Rust &amp; friends</A>
+        <DT><H3>Web Dev</H3>
+        <DL><p>
+            <DT><A HREF="https://developer.mozilla.org/" TAGS="docs,Web Dev">MDN</A>
+            <DD>The web's documentation
+        </DL><p>
+    </DL><p>
+    <DT><H3>Later</H3>
+    <DL><p>
+        <DT><A HREF="place:sort=8&maxResults=10">Recent tags</A>
+    </DL><p>
+    <DT><A HREF="https://example.com/">Example</A>
+</DL><p>
+"#;
+
+    #[test]
+    fn parse_test() {
+        let export = parse(EXPORT);
+        assert_eq!(vec![
+            Entry{url: String::from("https://www.rust-lang.org/"), title: String::from("Rust & friends"),
+                  tags: vec![], created_at: Some(Timespec::new(1545133589, 0)), ..Entry::default()},
+            Entry{url: String::from("https://developer.mozilla.org/"), title: String::from("MDN"),
+                  tags: vec![String::from("web-dev"), String::from("docs")], ..Entry::default()},
+            Entry{url: String::from("https://example.com/"), title: String::from("Example"),
+                  tags: vec![], ..Entry::default()},
+        ], export.entries);
+        assert_eq!(1, export.rejected.len());
+    }
+
+    #[test]
+    fn folder_after_heading_test() {
+        let export = parse("<dl><dt><h3>News</h3></dt><dl><dt><a href=\"https://lwn.net/\">LWN</a></dt></dl>\
+                            <dt><a href=\"https://example.com/\">Example</a></dt></dl>");
+        assert_eq!(vec![String::from("news")], export.entries[0].tags);
+        assert!(export.entries[1].tags.is_empty());
+    }
+}
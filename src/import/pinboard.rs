@@ -0,0 +1,66 @@
+//! Pinboard's JSON export, from `https://api.pinboard.in/v1/posts/all?format=json`
+//! or the settings page:
+//!
+//! ```json
+//! [{"href": "https://example.com/", "description": "Example", "extended": "",
+//!   "time": "2018-12-18T11:46:29Z", "tags": "rust web"}]
+//! ```
+//!
+//! `description` is the title, and tags are separated by spaces.
+
+use serde_json::{self, Value};
+
+use import::{iso_time, Entry, Export};
+use {normalize_tag, Error, Result};
+
+pub fn parse(json: &str) -> Result<Export> {
+    let value: Value = serde_json::from_str(json)
+        .map_err(|e| Error::Parse(format!("Not a Pinboard export: {}", e)))?;
+    let posts = value.as_array()
+        .ok_or_else(|| Error::Parse(String::from("Not a Pinboard export: expected a list of bookmarks")))?;
+
+    let mut export = Export::default();
+    for post in posts {
+        let field = |name: &str| post.get(name).and_then(Value::as_str).unwrap_or("");
+        let mut tags: Vec<String> = Vec::new();
+        for tag in field("tags").split_whitespace().map(normalize_tag) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        export.push(Entry{
+            url: field("href").trim().to_string(),
+            title: field("description").trim().to_string(),
+            tags,
+            created_at: iso_time(field("time")),
+            ..Entry::default()
+        });
+    }
+    Ok(export)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+
+    #[test]
+    fn parse_test() {
+        let export = parse(r#"[
+            {"href": "https://www.rust-lang.org/", "description": "Rust", "extended": "A language",
+             "meta": "0", "hash": "0", "time": "2018-12-18T11:46:29Z", "shared": "no", "toread": "no",
+             "tags": "rust Rust lang"},
+            {"href": "https://example.com/", "description": "", "time": "", "tags": ""},
+            {"description": "No link"}
+        ]"#).unwrap();
+        assert_eq!(vec![
+            Entry{url: String::from("https://www.rust-lang.org/"), title: String::from("Rust"),
+                  tags: vec![String::from("rust"), String::from("lang")],
+                  created_at: Some(Timespec::new(1545133589, 0)), ..Entry::default()},
+            Entry{url: String::from("https://example.com/"), ..Entry::default()},
+        ], export.entries);
+        assert_eq!(1, export.rejected.len());
+
+        assert!(parse("{}").is_err());
+    }
+}
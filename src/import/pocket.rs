@@ -0,0 +1,64 @@
+//! The HTML file Pocket exports, with a list of saved articles under each of
+//! "Unread" and "Read Archive":
+//!
+//! ```html
+//! <h1>Unread</h1>
+//! <ul>
+//!   <li><a href="https://example.com/" time_added="1545133589" tags="rust,web">Example</a></li>
+//! </ul>
+//! ```
+
+use select::document::Document;
+use select::predicate::Name;
+
+use import::{unix_time, Entry, Export};
+use normalize_tag;
+
+pub fn parse(html: &str) -> Export {
+    let doc = Document::from(html);
+    let mut export = Export::default();
+    for link in doc.find(Name("a")) {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in link.attr("tags").unwrap_or("").split(',').map(normalize_tag) {
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        export.push(Entry{
+            url: link.attr("href").unwrap_or("").trim().to_string(),
+            title: link.text().trim().to_string(),
+            tags,
+            created_at: link.attr("time_added").and_then(unix_time),
+            ..Entry::default()
+        });
+    }
+    export
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+
+    #[test]
+    fn parse_test() {
+        let export = parse(r#"<!DOCTYPE html>
+<html><head><title>Pocket Export</title></head><body>
+<h1>Unread</h1>
+<ul>
+<li><a href="https://www.rust-lang.org/" time_added="1545133589" tags="rust,Web Dev">Rust</a></li>
+</ul>
+<h1>Read Archive</h1>
+<ul>
+<li><a href="https://example.com/" time_added="" tags="">https://example.com/</a></li>
+</ul>
+</body></html>"#);
+        assert_eq!(vec![
+            Entry{url: String::from("https://www.rust-lang.org/"), title: String::from("Rust"),
+                  tags: vec![String::from("rust"), String::from("web-dev")],
+                  created_at: Some(Timespec::new(1545133589, 0)), ..Entry::default()},
+            Entry{url: String::from("https://example.com/"), title: String::from("https://example.com/"),
+                  ..Entry::default()},
+        ], export.entries);
+    }
+}
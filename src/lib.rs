@@ -1,9 +1,26 @@
 extern crate crypto;
 extern crate time;
 extern crate colored;
+extern crate url;
+extern crate reqwest;
+extern crate fs2;
+extern crate serde;
+extern crate serde_json;
+extern crate select;
+#[cfg(feature = "derive")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "sqlite")]
+#[macro_use]
+extern crate rusqlite;
+
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
 
 use crypto::md5::Md5;
 use crypto::digest::Digest;
+use std::cmp;
 use std::fmt;
 use std::env;
 
@@ -14,81 +31,679 @@ use std::io::prelude::*;
 use std::fs::File;
 
 use colored::*;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+#[cfg(feature = "derive")]
+use serde::de::{self, Deserialize, Deserializer};
+
+pub mod config;
+pub mod export;
+pub mod files;
+pub mod import;
+pub mod search;
+pub mod store;
+pub mod urls;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod error;
+
+pub use error::{Error, Result};
+
+use urls::UrlRules;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct Bookmark {
     pub hash: String,
     created_at: time::Tm,
     pub label: String,
     pub url: String,
     pub title: String,
-    tags: String,
+    tags: Vec<String>,
     pub custom_image: String,
+    metadata: Metadata,
+    last_visited: Option<time::Tm>,
+    visit_count: u32,
     //image: &'a str,
 }
 
-const ISO_TIME_DATE: &str = "%Y-%m-%dT%H:%M:%SZ";
+/// What the page says about itself, read when the bookmark is added. Any of
+/// it may be missing.
+#[derive(PartialEq, Debug, Clone, Default)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "derive", serde(default))]
+pub struct Metadata {
+    /// `meta description`, or `og:description` if there is none
+    pub description: String,
+    /// `og:site_name`
+    pub site_name: String,
+    /// The `rel=canonical` link
+    pub canonical: String,
+    /// The `lang` of the page's `<html>`
+    pub lang: String,
+    /// RSS, Atom and JSON feeds the page links to
+    pub feeds: Vec<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+}
+
+const ISO_TIME_DATE: &str = "%Y-%m-%dT%H:%M:%S%z";
 
 impl Bookmark {
-    pub fn new_from_line(line: String) -> Result<Bookmark, String> {
-        let fields: Vec<&str> = line.split("|").collect();
+    pub fn new_from_line(line: String) -> Result<Bookmark> {
+        let mut fields = split_line(&line).into_iter();
 
-        if fields.len() < 5{
-            return Err(String::from("Not enough fields in line"))
-        }
-        
-        let hash = String::from(fields[0]);
-        let created_at = time::strptime(fields[1], ISO_TIME_DATE).unwrap();
-        let label = String::from(fields[2]);
-        let url = String::from(fields[3]);
-        let title = String::from(fields[4]);
-        let tags = String::from(fields[5]);
-        let custom_image = String::from(fields[6]);
-        Ok(Bookmark{hash, created_at, label, url, title, tags, custom_image})
+        let (hash, created_at, label, url, title) = match (fields.next(), fields.next(), fields.next(),
+                                                           fields.next(), fields.next()) {
+            (Some(a), Some(b), Some(c), Some(d), Some(e)) => (a, b, c, d, e),
+            _ => return Err(Error::Parse(String::from("Not enough fields in line")))
+        };
+
+        let created_at = time::strptime(&created_at, ISO_TIME_DATE)?;
+        let label = match label.strip_prefix(':') {
+            Some(l) => l.to_string(),
+            None => label
+        };
+        // Lines written by bm may stop after the title, and rbm leaves off
+        // empty fields after the custom image
+        let tags = parse_tags(&fields.next().unwrap_or_default());
+        let custom_image = fields.next().unwrap_or_default();
+        let metadata = Metadata{
+            description: fields.next().unwrap_or_default(),
+            site_name: fields.next().unwrap_or_default(),
+            canonical: fields.next().unwrap_or_default(),
+            lang: fields.next().unwrap_or_default(),
+            feeds: fields.next().unwrap_or_default().split_whitespace().map(String::from).collect(),
+        };
+        let last_visited = match fields.next() {
+            Some(ref visited) if !visited.is_empty() => Some(time::strptime(visited, ISO_TIME_DATE)?),
+            _ => None
+        };
+        let visit_count = match fields.next() {
+            Some(ref count) if !count.is_empty() => count.parse::<u32>()
+                .map_err(|_| Error::Parse(format!("Invalid visit count {:?}", count)))?,
+            _ => 0
+        };
+        Ok(Bookmark{hash, created_at, label, url, title, tags, custom_image, metadata, last_visited, visit_count})
     }
 
+    /// A new bookmark, hashed with the default `UrlRules`
     pub fn new_from_input(url: String, title: String, tags: String, custom_image: String) -> Bookmark {
-        let mut hasher = Md5::new();
-        hasher.input_str(url.as_str());
-        let hash = hasher.result_str();
+        Bookmark::new_from_input_with(url, title, tags, custom_image, &UrlRules::default())
+    }
+
+    /// A new bookmark whose hash is taken from the normalized url. The url
+    /// itself is kept as given.
+    pub fn new_from_input_with(url: String, title: String, tags: String, custom_image: String,
+                               rules: &UrlRules) -> Bookmark {
+        let hash = url_hash(&rules.normalize(&url));
 
-        let created_at = time::now();
+        let created_at = now();
         // how to create label
         let label = hash[..5].to_string();
-        let tags = tags;
+        let tags = parse_tags(&tags);
         let custom_image = custom_image;
-        Bookmark{hash, created_at, label, url, title, tags, custom_image}
+        Bookmark{hash, created_at, label, url, title, tags, custom_image, metadata: Metadata::default(),
+                 last_visited: None, visit_count: 0}
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+    }
+
+    /// Changes the url and recomputes the hash from its normalized form. A
+    /// label generated from the old hash is regenerated from the new one.
+    pub fn set_url(&mut self, url: &str, rules: &UrlRules) {
+        let hash = url_hash(&rules.normalize(url));
+        if self.has_generated_label() {
+            self.label = hash[..5].to_string();
+        }
+        self.hash = hash;
+        self.url = url.to_string();
+    }
+
+    /// Starts a bookmark made entirely from what it is given. Unlike
+    /// `new_from_input` it doesn't read the clock, so the result is always
+    /// the same.
+    pub fn builder(url: &str, created_at: time::Timespec) -> BookmarkBuilder {
+        BookmarkBuilder{
+            url: url.to_string(),
+            created_at,
+            title: String::new(),
+            tags: Vec::new(),
+            label: None,
+            custom_image: String::new(),
+            metadata: Metadata::default(),
+            visit_count: 0,
+            last_visited: None,
+            rules: UrlRules::default(),
+        }
+    }
+
+    /// When the bookmark was added
+    pub fn created_at(&self) -> &time::Tm {
+        &self.created_at
+    }
+
+    /// Backdates the bookmark, for ones brought over from elsewhere. The
+    /// time is kept in UTC.
+    pub fn set_created_at(&mut self, at: time::Timespec) {
+        self.created_at = utc(at);
+    }
+
+    pub fn last_visited(&self) -> Option<&time::Tm> {
+        self.last_visited.as_ref()
+    }
+
+    pub fn visit_count(&self) -> u32 {
+        self.visit_count
+    }
+
+    /// Where the custom image, or else the downloaded one, is saved. None if
+    /// neither is there or `RBM_BASE` isn't set.
+    pub fn image_path(&self) -> Option<String> {
+        let base_path = base_dir().ok()?;
+        image_exists(&base_path, &self.custom_image)
+            .or_else(|| image_exists(&base_path, &self.hash))
+            .map(|name| format!("{}/.bm.shots/{}", base_path, name))
+    }
+
+    /// Replaces the visits, for ones counted elsewhere
+    pub fn set_visits(&mut self, count: u32, last_visited: Option<time::Timespec>) {
+        self.visit_count = count;
+        self.last_visited = last_visited.map(utc);
+    }
+
+    /// Records a visit now
+    pub fn visit(&mut self) {
+        self.last_visited = Some(now());
+        self.visit_count = self.visit_count.saturating_add(1);
+    }
+
+    /// How much the bookmark is used: its visits, weighted by how recently
+    /// it was last visited as Firefox does. Never visited bookmarks score 0,
+    /// and visits with no date count as the oldest.
+    pub fn frecency(&self, now: time::Timespec) -> u64 {
+        let last = match self.last_visited {
+            Some(ref visited) => timestamp(visited),
+            None if self.visit_count > 0 => return u64::from(self.visit_count) * 10,
+            None => return 0
+        };
+        let weight = match (now - last).num_days() {
+            d if d <= 4 => 100,
+            d if d <= 14 => 70,
+            d if d <= 31 => 50,
+            d if d <= 90 => 30,
+            _ => 10
+        };
+        u64::from(self.visit_count.max(1)) * weight
+    }
+
+    /// True if the label is a prefix of the hash rather than one chosen with
+    /// `set_label`
+    pub fn has_generated_label(&self) -> bool {
+        self.label.len() >= 5 && self.hash.starts_with(&self.label)
+    }
+
+    /// Sets a chosen label, dropping a leading `:`. Labels can't be empty or
+    /// contain whitespace.
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        let label = label.strip_prefix(':').unwrap_or(label);
+        if label.is_empty() || label.contains(char::is_whitespace) {
+            return Err(Error::Parse(format!("Labels can't be empty or contain spaces: {:?}", label)));
+        }
+        self.label = label.to_string();
+        Ok(())
+    }
+
+    pub fn set_custom_image(&mut self, custom_image: &str) {
+        self.custom_image = custom_image.to_string();
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Feeds are stored space separated, so whitespace in them is escaped
+    /// and empty ones dropped
+    pub fn set_metadata(&mut self, mut metadata: Metadata) {
+        metadata.feeds = metadata.feeds.iter()
+            .map(|feed| feed.split_whitespace().collect::<Vec<&str>>().join("%20"))
+            .filter(|feed| !feed.is_empty())
+            .collect();
+        self.metadata = metadata;
+    }
+
+    /// Normalized tags, in the order they were first added
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = normalize_tag(tag);
+        self.tags.contains(&tag)
+    }
+
+    /// Replaces the tags with those in a comma separated list
+    pub fn set_tags(&mut self, tags: &str) {
+        self.tags = parse_tags(tags);
+    }
+
+    /// Returns false if the tag was empty or already present
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        let tag = normalize_tag(tag);
+        if tag.is_empty() || self.tags.contains(&tag) {
+            return false
+        }
+        self.tags.push(tag);
+        true
+    }
+
+    /// Returns false if the bookmark didn't have the tag
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let tag = normalize_tag(tag);
+        let before = self.tags.len();
+        self.tags.retain(|t| *t != tag);
+        self.tags.len() != before
+    }
+
+    /// Renames a tag in place, dropping it instead if the bookmark already
+    /// has the new tag. Returns false if the bookmark didn't have the tag, or
+    /// the names are the same once normalized
+    pub fn rename_tag(&mut self, from: &str, to: &str) -> bool {
+        let from = normalize_tag(from);
+        let to = normalize_tag(to);
+        if from == to {
+            return false
+        }
+        let position = match self.tags.iter().position(|t| *t == from) {
+            Some(p) => p,
+            None => return false
+        };
+        if to.is_empty() || self.tags.contains(&to) {
+            self.tags.remove(position);
+        } else {
+            self.tags[position] = to;
+        }
+        true
+    }
+
+    /// True for the same link saved twice: the same hash, or urls that are
+    /// the same once normalized. Bookmarks saved before urls were normalized
+    /// are hashed from the url as typed.
+    pub fn is_duplicate_of(&self, other: &Bookmark, rules: &UrlRules) -> bool {
+        self.hash == other.hash || rules.normalize(&self.url) == rules.normalize(&other.url)
+    }
+
+    /// Takes the other bookmark's tags and visits, and its custom image and
+    /// metadata if this one has none. Returns false if nothing changed
+    pub fn merge(&mut self, other: &Bookmark) -> bool {
+        let mut changed = false;
+        for tag in &other.tags {
+            changed |= self.add_tag(tag);
+        }
+        if self.custom_image.is_empty() && !other.custom_image.is_empty() {
+            self.custom_image = other.custom_image.clone();
+            changed = true;
+        }
+        if self.metadata.is_empty() && !other.metadata.is_empty() {
+            self.metadata = other.metadata.clone();
+            changed = true;
+        }
+        if other.visit_count > 0 {
+            self.visit_count = self.visit_count.saturating_add(other.visit_count);
+            changed = true;
+        }
+        if other.last_visited.map(|t| timestamp(&t)) > self.last_visited.map(|t| timestamp(&t)) {
+            self.last_visited = other.last_visited;
+            changed = true;
+        }
+        changed
     }
 
     pub fn output(&self) -> String {
-        return [
+        let mut fields = vec![
             self.hash.to_owned(),
-            time::strftime(ISO_TIME_DATE, &self.created_at).unwrap(),
-            format!(":{}", &self.label),
+            self.created_at.rfc3339().to_string(),
+            format!(":{}", self.label),
             self.url.to_owned(),
             self.title.to_owned(),
-            self.tags.to_owned(),
+            self.tags.join(","),
             self.custom_image.to_owned(),
-        ].join("|");
+            self.metadata.description.to_owned(),
+            self.metadata.site_name.to_owned(),
+            self.metadata.canonical.to_owned(),
+            self.metadata.lang.to_owned(),
+            self.metadata.feeds.join(" "),
+            self.last_visited.map(|t| t.rfc3339().to_string()).unwrap_or_default(),
+            if self.visit_count == 0 { String::new() } else { self.visit_count.to_string() },
+        ];
+        // Keeps lines without the newer fields in bm's format
+        while fields.len() > 7 && fields.last().map(String::is_empty) == Some(true) {
+            fields.pop();
+        }
+        fields.iter().map(|f| escape_field(f)).collect::<Vec<String>>().join("|")
+    }
+
+    /// The page metadata and visits, a line each, for `list --long`
+    pub fn details(&self) -> String {
+        let m = &self.metadata;
+        let feeds = m.feeds.join(" ");
+        let visited = match (self.visit_count, self.last_visited) {
+            (0, None) => String::new(),
+            (count, None) => format!("{} times", count),
+            (count, Some(last)) => format!("{} times, last {}", count.max(1), last.rfc3339()),
+        };
+        let mut details = String::new();
+        for (name, value) in [("Description", &m.description), ("Site", &m.site_name), ("Canonical", &m.canonical),
+                              ("Language", &m.lang), ("Feeds", &feeds), ("Visited", &visited)].iter() {
+            if !value.is_empty() {
+                details.push_str(&format!("{} {}\n", format!("{}:", name).bright_black(), value));
+            }
+        }
+        details
+    }
+}
+
+/// The stored fields, as `rbm list --format json` writes them less `image`.
+/// Times are RFC 3339.
+impl Serialize for Bookmark {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_bookmark(self, None, serializer)
+    }
+}
+
+/// A bookmark serialized with `image`, the path of its custom or downloaded
+/// image if there is one under `$RBM_BASE`
+pub(crate) struct WithImage<'a>(pub &'a Bookmark);
+
+impl<'a> Serialize for WithImage<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_bookmark(self.0, Some(self.0.image_path()), serializer)
+    }
+}
+
+fn serialize_bookmark<S: Serializer>(b: &Bookmark, image: Option<Option<String>>, serializer: S)
+                                     -> std::result::Result<S::Ok, S::Error> {
+    let mut s = serializer.serialize_struct("Bookmark", if image.is_some() { 15 } else { 14 })?;
+    s.serialize_field("label", &b.label)?;
+    s.serialize_field("hash", &b.hash)?;
+    s.serialize_field("url", &b.url)?;
+    s.serialize_field("title", &b.title)?;
+    s.serialize_field("tags", &b.tags)?;
+    s.serialize_field("created_at", &b.created_at.rfc3339().to_string())?;
+    s.serialize_field("last_visited", &b.last_visited.map(|t| t.rfc3339().to_string()))?;
+    s.serialize_field("visit_count", &b.visit_count)?;
+    s.serialize_field("custom_image", &b.custom_image)?;
+    if let Some(image) = image {
+        s.serialize_field("image", &image)?;
+    }
+    s.serialize_field("description", &b.metadata.description)?;
+    s.serialize_field("site_name", &b.metadata.site_name)?;
+    s.serialize_field("canonical", &b.metadata.canonical)?;
+    s.serialize_field("lang", &b.metadata.lang)?;
+    s.serialize_field("feeds", &b.metadata.feeds)?;
+    s.end()
+}
+
+/// What `Bookmark` serializes to. Only the url and created_at are needed;
+/// a missing hash and label are made from the url, and `image` is ignored.
+#[cfg(feature = "derive")]
+#[derive(Deserialize)]
+struct BookmarkFields {
+    url: String,
+    created_at: String,
+    #[serde(default)]
+    hash: String,
+    #[serde(default)]
+    label: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    custom_image: String,
+    #[serde(default)]
+    last_visited: Option<String>,
+    #[serde(default)]
+    visit_count: u32,
+    #[serde(flatten)]
+    metadata: Metadata,
+}
+
+#[cfg(feature = "derive")]
+impl<'de> Deserialize<'de> for Bookmark {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Bookmark, D::Error> {
+        let fields = BookmarkFields::deserialize(deserializer)?;
+        let parse = |t: &str| time::strptime(t, ISO_TIME_DATE).map_err(de::Error::custom);
+        let created_at = parse(&fields.created_at)?;
+        let last_visited = match fields.last_visited {
+            Some(ref visited) => Some(parse(visited)?),
+            None => None
+        };
+        let hash = match fields.hash {
+            ref hash if hash.is_empty() => url_hash(&UrlRules::default().normalize(&fields.url)),
+            ref hash if hash.len() < 5 || !hash.chars().all(|c| c.is_ascii_alphanumeric()) =>
+                return Err(de::Error::custom(format!("Invalid hash {:?}, expected at least 5 letters or digits", hash))),
+            hash => hash
+        };
+        let mut b = Bookmark{label: hash[..5].to_string(), hash, created_at, url: fields.url, title: fields.title,
+                             tags: Vec::new(), custom_image: fields.custom_image, metadata: Metadata::default(),
+                             last_visited, visit_count: fields.visit_count};
+        if !fields.label.is_empty() {
+            b.set_label(&fields.label).map_err(de::Error::custom)?;
+        }
+        for tag in &fields.tags {
+            b.add_tag(tag);
+        }
+        b.set_metadata(fields.metadata);
+        Ok(b)
     }
 }
 
 impl fmt::Display for Bookmark {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
         write!(f, "{} {} {} [{}]\n{} — {}{}\n",
-               self.label.bold().bright_black(),
+               format!(":{}", self.label).bold().bright_black(),
                "→".dimmed(),
                self.title.white(),
-               self.tags.bold(),
+               self.tags.join(",").bold(),
                self.url.underline().dimmed(),
                "Added: ".bright_black(),
-               time::strftime(ISO_TIME_DATE, &self.created_at).unwrap().bright_black())
+               self.created_at.rfc3339().to_string().bright_black())
     }
 }
 
-fn image_exists(filename: &str) -> Option<String>{
-    let base_path = env::var("RBM_BASE").unwrap();
+/// The current local time, truncated to what the file stores
+fn now() -> time::Tm {
+    time::strptime(&time::now().rfc3339().to_string(), ISO_TIME_DATE).expect("Unparseable rfc3339 time")
+}
+
+/// A `Bookmark` under construction, from `Bookmark::builder`
+#[derive(Debug, Clone)]
+pub struct BookmarkBuilder {
+    url: String,
+    created_at: time::Timespec,
+    title: String,
+    tags: Vec<String>,
+    label: Option<String>,
+    custom_image: String,
+    metadata: Metadata,
+    visit_count: u32,
+    last_visited: Option<time::Timespec>,
+    rules: UrlRules,
+}
+
+impl BookmarkBuilder {
+    pub fn title(mut self, title: &str) -> BookmarkBuilder {
+        self.title = title.to_string();
+        self
+    }
 
+    /// Adds a tag, normalized as `add_tag` does
+    pub fn tag(mut self, tag: &str) -> BookmarkBuilder {
+        let tag = normalize_tag(tag);
+        if !tag.is_empty() && !self.tags.contains(&tag) {
+            self.tags.push(tag);
+        }
+        self
+    }
+
+    /// A chosen label, instead of the first five characters of the hash
+    pub fn label(mut self, label: &str) -> BookmarkBuilder {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn custom_image(mut self, custom_image: &str) -> BookmarkBuilder {
+        self.custom_image = custom_image.to_string();
+        self
+    }
+
+    pub fn metadata(mut self, metadata: Metadata) -> BookmarkBuilder {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn visits(mut self, count: u32, last_visited: Option<time::Timespec>) -> BookmarkBuilder {
+        self.visit_count = count;
+        self.last_visited = last_visited;
+        self
+    }
+
+    /// How the url is normalized before hashing, the defaults otherwise
+    pub fn rules(mut self, rules: &UrlRules) -> BookmarkBuilder {
+        self.rules = rules.clone();
+        self
+    }
+
+    /// Fails only if the label isn't one `set_label` accepts
+    pub fn build(self) -> Result<Bookmark> {
+        let hash = url_hash(&self.rules.normalize(&self.url));
+        let mut b = Bookmark{
+            label: hash[..5].to_string(),
+            hash,
+            created_at: utc(self.created_at),
+            url: self.url,
+            title: self.title,
+            tags: self.tags,
+            custom_image: self.custom_image,
+            metadata: Metadata::default(),
+            last_visited: None,
+            visit_count: 0,
+        };
+        if let Some(label) = self.label {
+            b.set_label(&label)?;
+        }
+        b.set_metadata(self.metadata);
+        b.set_visits(self.visit_count, self.last_visited);
+        Ok(b)
+    }
+}
+
+/// A time since the epoch as UTC, truncated like `now`
+fn utc(at: time::Timespec) -> time::Tm {
+    time::strptime(&time::at_utc(at).rfc3339().to_string(), ISO_TIME_DATE).expect("Unparseable rfc3339 time")
+}
+
+/// Time since the epoch, honouring the stored UTC offset. `Tm::to_timespec`
+/// treats every non-UTC time as being in the local timezone.
+fn timestamp(tm: &time::Tm) -> time::Timespec {
+    let mut utc = *tm;
+    utc.tm_utcoff = 0;
+    utc.to_timespec() - time::Duration::seconds(i64::from(tm.tm_utcoff))
+}
+
+/// Escapes the field separator, line breaks and the escape character itself
+/// so any text can be stored in a field. Text without them is unchanged, so
+/// files stay readable by bm.
+fn escape_field(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '|' => escaped.push_str("\\|"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Splits a line on unescaped `|` and unescapes each field. Unknown escapes
+/// and a trailing backslash are kept as they are.
+fn split_line(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => field.push('\n'),
+                Some('r') => field.push('\r'),
+                Some(e @ '|') | Some(e @ '\\') => field.push(e),
+                Some(e) => {
+                    field.push('\\');
+                    field.push(e);
+                },
+                None => field.push('\\')
+            },
+            '|' => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c)
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Lowercases a tag and replaces runs of whitespace and commas with a single
+/// `-`, so it can be used as a CSS class and isn't split when read back
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+        .to_lowercase()
+}
+
+/// Parses a comma separated tag list, dropping empty and duplicate tags
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = Vec::new();
+    for tag in tags.split(',').map(normalize_tag) {
+        if !tag.is_empty() && !parsed.contains(&tag) {
+            parsed.push(tag);
+        }
+    }
+    parsed
+}
+
+/// The shortest prefix of the hash, of at least five characters, that isn't
+/// one of the taken labels. A hash taken in full is numbered.
+pub fn unique_label(hash: &str, taken: &[String]) -> String {
+    let free = |label: &str| !taken.iter().any(|t| t == label);
+    (5..=hash.len()).filter_map(|len| hash.get(..len))
+        .find(|label| free(label))
+        .map(String::from)
+        .unwrap_or_else(|| (2..).map(|n| format!("{}-{}", hash, n)).find(|label| free(label)).unwrap())
+}
+
+fn url_hash(url: &str) -> String {
+    let mut hasher = Md5::new();
+    hasher.input_str(url);
+    hasher.result_str()
+}
+
+/// The directory holding bm.lnk, the page template and the images, from
+/// `RBM_BASE`
+pub fn base_dir() -> Result<String> {
+    env::var("RBM_BASE").map_err(|_| Error::Config(String::from("Set RBM_BASE env")))
+}
+
+fn image_exists(base_path: &str, filename: &str) -> Option<String>{
     if filename.is_empty() {
         return None
     };
@@ -105,32 +720,108 @@ fn image_exists(filename: &str) -> Option<String>{
     return None;
 }
 
-pub fn html_output(bookmarks: Vec<Bookmark>) -> String {
-    let image_path = env::var("RBM_BASE").unwrap();
-    
-    let mut file = File::open(format!("{}/.template.html", &image_path)).expect("Unable to open the file");
+/// How bookmarks are ordered in lists and on the page
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "derive", serde(rename_all = "lowercase"))]
+pub enum Order {
+    /// As they are stored
+    File,
+    /// Most used first, see `sort_by_frecency`
+    Frecency,
+}
+
+impl Order {
+    pub fn parse(name: &str) -> Result<Order> {
+        match name {
+            "file" => Ok(Order::File),
+            "frecency" => Ok(Order::Frecency),
+            _ => Err(Error::Parse(format!("Unknown order {}, expected file or frecency", name)))
+        }
+    }
+
+    pub fn sort(self, bookmarks: &mut [Bookmark]) {
+        if self == Order::Frecency {
+            sort_by_frecency(bookmarks);
+        }
+    }
+}
+
+/// Most used first, by `Bookmark::frecency`. Bookmarks that score the same
+/// keep their order.
+pub fn sort_by_frecency(bookmarks: &mut [Bookmark]) {
+    let now = time::get_time();
+    bookmarks.sort_by_key(|b| cmp::Reverse(b.frecency(now)));
+}
+
+/// The title, with the site name and description when the page gave them
+fn tooltip(b: &Bookmark) -> String {
+    let mut lines = vec![b.title.as_str()];
+    if !b.metadata.site_name.is_empty() && b.metadata.site_name != b.title {
+        lines.push(&b.metadata.site_name);
+    }
+    if !b.metadata.description.is_empty() {
+        lines.push(&b.metadata.description);
+    }
+    lines.join("\n")
+}
+
+fn escape_attribute(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// One bookmark's tile in bm.html, with everything from the bookmark escaped
+fn html_tile(bm: &Bookmark, href: &str, image_path: Option<&str>) -> String {
+    let tagstring = escape_attribute(&bm.tags.join(" "));
+    let tooltip = escape_attribute(&tooltip(bm));
+    let href = escape_attribute(href);
+    let title = escape_attribute(&bm.title);
+    match image_path {
+        Some(path) => format!("<div class=\"bm {}\"><a href='{}' title='{}'><img src='.bm.shots/{}'><p>{}</p></a></div>",
+                              tagstring, href, tooltip, escape_attribute(path), title),
+        None => format!("<div class=\"bm noimage {}\"><a href='{}' title='{}'><div class=\"letter\">{}</div><p>{}</p></a></div>",
+                        tagstring, href, tooltip, escape_attribute(&bm.title.chars().next().unwrap_or('?').to_string()),
+                        title)
+    }
+}
+
+pub fn html_output(bookmarks: Vec<Bookmark>) -> Result<String> {
+    html_output_via(bookmarks, None)
+}
+
+/// The page, with tiles linking to `redirect` followed by the hash rather
+/// than straight to the url, so visits can be recorded
+pub fn html_output_via(bookmarks: Vec<Bookmark>, redirect: Option<&str>) -> Result<String> {
+    let base_path = base_dir()?;
+    let template_path = format!("{}/.template.html", &base_path);
+
     let mut contents = String::new();
-    file.read_to_string(&mut contents).expect("Unable to read the file");
+    File::open(&template_path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| Error::Template(format!("Unable to read {}: {}", template_path, e)))?;
     
     let mut buffer = String::new();
     // convert to map
     for bm in bookmarks {
-        let tagstring = bm.tags.replace(",", " ");
-        let image_path = image_exists(&bm.custom_image).or(image_exists(&bm.hash));
-        let icon_tag = match image_path{
-            Some(path) =>  format!("<div class=\"bm {}\"><a href='{}'><img src='.bm.shots/{}'><p>{}</p></a></div>",
-                                  tagstring, bm.url, path, bm.title),
-            None => format!("<div class=\"bm noimage {}\"><a href='{}'><div class=\"letter\">{}</div><p>{}</p></a></div>",
-                           tagstring, bm.url, bm.title.chars().next().expect("No title"), bm.title)};
-        buffer.push_str(&icon_tag);
+        let href = match redirect {
+            Some(prefix) => format!("{}{}", prefix, bm.hash),
+            None => bm.url.clone()
+        };
+        let image_path = image_exists(&base_path, &bm.custom_image).or_else(|| image_exists(&base_path, &bm.hash));
+        buffer.push_str(&html_tile(&bm, &href, image_path.as_deref()));
     }
 
-    return contents.replace("//REPLACE//", &buffer);
+    Ok(contents.replace("//REPLACE//", &buffer))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use quickcheck::{Arbitrary, Gen};
 
     #[test]
     fn output_test() {
@@ -140,8 +831,11 @@ mod tests {
             label: String::from("5"),
             url: String::from("https://www.example.com/"),
             title: String::from("Example"),
-            tags: String::from("tag1,tag2"),
-            custom_image: String::from("test")};
+            tags: vec![String::from("tag1"), String::from("tag2")],
+            custom_image: String::from("test"),
+            metadata: Metadata::default(),
+            last_visited: None,
+            visit_count: 0};
 
         assert_eq!(b.output(), "a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example|tag1,tag2|test");
     }
@@ -153,11 +847,391 @@ mod tests {
         assert_eq!(Bookmark{
             hash: String::from("a123"),
             created_at: time::strptime("2017-12-18T11:46:29Z", ISO_TIME_DATE).unwrap(),
-            label: String::from(":5"),
+            label: String::from("5"),
             url: String::from("https://www.example.com/"),
             title: String::from("Example"),
-            tags: String::from("tag1,tag2"),
-            custom_image: String::from("test")}, Bookmark::new_from_line(line).unwrap())
+            tags: vec![String::from("tag1"), String::from("tag2")],
+            custom_image: String::from("test"),
+            metadata: Metadata::default(),
+            last_visited: None,
+            visit_count: 0}, Bookmark::new_from_line(line).unwrap())
+    }
+
+    #[test]
+    fn relabelled_output_test() {
+        let line = String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example|tag1,tag2|test");
+
+        assert_eq!(line, Bookmark::new_from_line(line.clone()).unwrap().output());
+    }
+
+    #[test]
+    fn set_url_test() {
+        let mut b = Bookmark::new_from_input(String::from("https://www.example.com/"), String::from("Example"),
+                                             String::from("tag1"), String::new());
+        b.set_url("HTTPS://www.example.org?utm_source=rss", &UrlRules::default());
+
+        assert_eq!(url_hash("https://www.example.org/"), b.hash);
+        assert_eq!(&b.hash[..5], b.label);
+        assert_eq!("HTTPS://www.example.org?utm_source=rss", b.url);
+    }
+
+    #[test]
+    fn label_test() {
+        let mut b = Bookmark::new_from_input(String::from("https://www.example.com/"), String::from("Example"),
+                                             String::from("tag1"), String::new());
+        assert!(b.has_generated_label());
+        b.label = unique_label(&b.hash, &[b.hash[..5].to_string(), b.hash[..6].to_string()]);
+        assert_eq!(&b.hash[..7], b.label);
+        assert!(b.has_generated_label());
+
+        b.set_label(":docs").unwrap();
+        assert_eq!("docs", b.label);
+        assert!(!b.has_generated_label());
+        assert!(b.set_label("two words").is_err());
+        assert!(b.set_label(":").is_err());
+
+        b.set_url("https://www.example.org/", &UrlRules::default());
+        assert_eq!("docs", b.label);
+
+        assert_eq!("abcde", unique_label("abcdef", &[]));
+        assert_eq!("abcdef-3", unique_label("abcdef", &["abcde".to_string(), "abcdef".to_string(),
+                                                       "abcdef-2".to_string()]));
+    }
+
+    #[test]
+    fn edit_tags_test() {
+        let mut b = Bookmark::new_from_input(String::from("https://www.example.com/"), String::from("Example"),
+                                             String::from("tag1,tag2"), String::new());
+        assert!(!b.add_tag("TAG2"));
+        assert!(b.add_tag("tag3"));
+        assert!(b.remove_tag("tag1"));
+        assert!(!b.remove_tag("tag1"));
+
+        assert_eq!(["tag2", "tag3"], b.tags());
+        assert!(b.has_tag("Tag3"));
+    }
+
+    #[test]
+    fn rename_tag_test() {
+        let mut b = Bookmark::new_from_input(String::from("https://www.example.com/"), String::from("Example"),
+                                             String::from("rustlang,web,rust"), String::new());
+        assert!(b.rename_tag("web", "www"));
+        assert!(b.rename_tag("rustlang", "Rust"));
+        assert!(!b.rename_tag("missing", "rust"));
+        assert!(!b.rename_tag("Rust", "rust"));
+
+        assert_eq!(["www", "rust"], b.tags());
+    }
+
+    #[test]
+    fn duplicate_test() {
+        let mut a = Bookmark::new_from_input(String::from("https://www.example.com/"), String::from("Example"),
+                                             String::from("tag1"), String::new());
+        let b = Bookmark::new_from_input(String::from("HTTPS://WWW.Example.com:443"), String::from("Other"),
+                                         String::from("tag2,tag1"), String::from("icon.png"));
+        let c = Bookmark::new_from_input(String::from("https://www.example.com/page"), String::from("Example"),
+                                         String::from("tag1"), String::new());
+        let rules = UrlRules::default();
+        assert!(a.is_duplicate_of(&b, &rules));
+        assert!(!a.is_duplicate_of(&c, &rules));
+
+        assert!(a.merge(&b));
+        assert!(!a.merge(&b));
+        assert_eq!(["tag1", "tag2"], a.tags());
+        assert_eq!("Example", a.title);
+        assert_eq!("icon.png", a.custom_image);
+
+        let mut visited = c.clone();
+        visited.set_visits(3, Some(time::Timespec::new(1545133589, 0)));
+        a.set_visits(2, Some(time::Timespec::new(1545000000, 0)));
+        assert!(a.merge(&visited));
+        assert_eq!(5, a.visit_count());
+        assert_eq!(Some(time::Timespec::new(1545133589, 0)), a.last_visited().map(timestamp));
+        visited.set_visits(u32::max_value(), None);
+        assert!(a.merge(&visited));
+        assert_eq!(u32::max_value(), a.visit_count());
+        assert_eq!(Some(time::Timespec::new(1545133589, 0)), a.last_visited().map(timestamp));
+    }
+
+    #[test]
+    fn parse_tags_test() {
+        assert_eq!(vec!["rust", "web-dev", "go"], parse_tags(" Rust,web  dev,,RUST,go "));
+        assert!(parse_tags("").is_empty());
+        assert_eq!("a-b", normalize_tag(" A, b "));
+    }
+
+    #[test]
+    fn short_line_test() {
+        let b = Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example")).unwrap();
+
+        assert_eq!("Example", b.title);
+        assert!(b.tags().is_empty());
+        assert_eq!("", b.custom_image);
+        assert!(Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/")).is_err());
+    }
+
+    #[test]
+    fn metadata_test() {
+        let line = "a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example|tag1|\
+                    |An example \\| page|Example|https://example.com/|en|https://example.com/rss https://example.com/atom";
+        let b = Bookmark::new_from_line(String::from(line)).unwrap();
+        assert_eq!("An example | page", b.metadata().description);
+        assert_eq!(vec!["https://example.com/rss", "https://example.com/atom"], b.metadata().feeds);
+        assert_eq!(line, b.output());
+
+        let mut b = Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example")).unwrap();
+        b.set_metadata(Metadata{lang: String::from("en"), feeds: vec![String::from(" /a feed "), String::new()],
+                                ..Metadata::default()});
+        assert_eq!("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example||||||en|/a%20feed", b.output());
+        b.set_metadata(Metadata{description: String::from("A page"), ..Metadata::default()});
+        assert_eq!("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example|||A page", b.output());
+        b.set_metadata(Metadata::default());
+        assert_eq!("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example||", b.output());
+        assert_eq!("Example", tooltip(&b));
+    }
+
+    #[test]
+    fn builder_test() {
+        let b = Bookmark::builder("https://www.example.com/?utm_source=x", time::Timespec::new(1513597589, 0))
+            .title("Example")
+            .tag("Web Dev")
+            .tag("web-dev")
+            .visits(2, Some(time::Timespec::new(1545133589, 0)))
+            .build()
+            .unwrap();
+        let same = Bookmark::new_from_input(String::from("https://www.example.com/"), String::new(),
+                                            String::new(), String::new());
+        assert_eq!(same.hash, b.hash);
+        assert_eq!(&same.hash[..5], b.label);
+        assert_eq!(format!("{}|2017-12-18T11:46:29Z|:{}|https://www.example.com/?utm_source=x|Example|web-dev|||||||2018-12-18T11:46:29Z|2",
+                           b.hash, b.label), b.output());
+        assert_eq!(b, Bookmark::new_from_line(b.output()).unwrap());
+
+        let labelled = Bookmark::builder("https://example.com/", time::Timespec::new(0, 0)).label(":home").build().unwrap();
+        assert_eq!("home", labelled.label);
+        assert!(Bookmark::builder("https://example.com/", time::Timespec::new(0, 0)).label("a b").build().is_err());
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn deserialize_test() {
+        let b = Bookmark::new_from_line(String::from(
+            "a1234|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example|rust,web||A page|||en||2019-01-02T03:04:05+01:00|3")).unwrap();
+        let json = serde_json::to_string(&b).unwrap();
+        assert_eq!(b, serde_json::from_str(&json).unwrap());
+
+        let with = |extra: &str| serde_json::from_str::<Bookmark>(
+            &format!("{{\"url\": \"https://www.example.com/\", \"created_at\": \"2017-12-18T11:46:29Z\", {}}}", extra));
+        assert!(with("\"hash\": \"ab\"").is_err());
+        assert!(with("\"hash\": \"abcdé\"").is_err());
+        assert!(with("\"label\": \"a b\"").is_err());
+        assert_eq!("home", with("\"label\": \":home\"").unwrap().label);
+        let b = with("\"tags\": [\"Rust\", \"rust\", \" \", \"a,b\"], \"feeds\": [\"https://x.com/a feed\", \"\"]").unwrap();
+        assert_eq!(["rust", "a-b"], b.tags());
+        assert_eq!(vec!["https://x.com/a%20feed"], b.metadata().feeds);
+        assert_eq!(b, Bookmark::new_from_line(b.output()).unwrap());
+
+        let b: Bookmark = serde_json::from_str("{\"url\": \"https://www.example.com/\", \"created_at\": \"2017-12-18T11:46:29Z\"}").unwrap();
+        assert_eq!(Bookmark::new_from_input(b.url.clone(), String::new(), String::new(), String::new()).hash, b.hash);
+        assert!(serde_json::from_str::<Bookmark>("{\"url\": \"https://www.example.com/\", \"created_at\": \"today\"}").is_err());
+
+        assert_eq!(Order::Frecency, serde_json::from_str("\"frecency\"").unwrap());
+        let rules: UrlRules = serde_json::from_str("{\"drop_fragment\": true}").unwrap();
+        assert_eq!(UrlRules{drop_fragment: true, ..UrlRules::default()}, rules);
+    }
+
+    #[test]
+    fn visit_test() {
+        let line = "a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example||||||||2019-01-02T03:04:05+01:00";
+        let mut b = Bookmark::new_from_line(String::from(line)).unwrap();
+        assert_eq!(timestamp(&time::strptime("2019-01-02T02:04:05Z", ISO_TIME_DATE).unwrap()),
+                   timestamp(b.last_visited().unwrap()));
+        assert_eq!(line, b.output());
+
+        b.visit();
+        b.visit();
+        assert!(timestamp(b.last_visited().unwrap()) >= timestamp(&b.created_at));
+        assert_eq!(2, b.visit_count());
+        assert!(b.output().ends_with("|2"));
+        assert_eq!(b, Bookmark::new_from_line(b.output()).unwrap());
+        assert!(Bookmark::new_from_line(format!("{}|not a date", &line[..line.len() - 26])).is_err());
+        assert!(Bookmark::new_from_line(format!("{}|-1", line)).is_err());
+
+        b.set_visits(3, Some(time::Timespec::new(1545133589, 0)));
+        b.set_created_at(time::Timespec::new(1513597589, 0));
+        assert!(b.output().starts_with("a123|2017-12-18T11:46:29Z|"));
+        assert!(b.output().ends_with("|2018-12-18T11:46:29Z|3"));
+    }
+
+    #[test]
+    fn frecency_test() {
+        let line = "a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example||||||||2019-01-02T00:00:00Z";
+        let at = |date: &str| timestamp(&time::strptime(date, ISO_TIME_DATE).unwrap());
+        let once = Bookmark::new_from_line(String::from(line)).unwrap();
+        let often = Bookmark::new_from_line(format!("{}|5", line)).unwrap();
+        let never = Bookmark::new_from_line(String::from(&line[..line.len() - 20])).unwrap();
+
+        assert_eq!(100, once.frecency(at("2019-01-03T00:00:00Z")));
+        assert_eq!(10, once.frecency(at("2020-01-03T00:00:00Z")));
+        assert_eq!(500, often.frecency(at("2019-01-03T00:00:00Z")));
+        assert_eq!(0, never.frecency(at("2019-01-03T00:00:00Z")));
+        let undated = Bookmark::new_from_line(format!("{}|3", &line[..line.len() - 20])).unwrap();
+        assert_eq!(30, undated.frecency(at("2019-01-03T00:00:00Z")));
+
+        let mut bookmarks = vec![never.clone(), once.clone(), often.clone()];
+        sort_by_frecency(&mut bookmarks);
+        assert_eq!(vec![often, once, never], bookmarks);
+    }
+
+    #[test]
+    fn html_tile_test() {
+        let mut b = Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example")).unwrap();
+        b.set_title("<script>alert(1)</script>");
+        let tile = html_tile(&b, "https://x.com/?a='b'&c=1", Some("a123.png"));
+        assert!(!tile.contains("<script>"));
+        assert!(tile.contains("<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>"));
+        assert!(tile.contains("href='https://x.com/?a=&#39;b&#39;&amp;c=1'"));
+        assert!(html_tile(&b, &b.url, None).contains("<div class=\"letter\">&lt;</div>"));
+    }
+
+    #[test]
+    fn escaped_output_test() {
+        let mut b = Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example")).unwrap();
+        b.set_title("Foo | Bar\\Baz\nQux");
+
+        assert_eq!("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Foo \\| Bar\\\\Baz\\nQux||", b.output());
+    }
+
+    #[test]
+    fn hostile_round_trip_test() {
+        let hostile = ["|", "\\", "\\|", "|\\", "a|b|c|d|e|f|g", "\\n", "line\nbreak", "\r\n", "trailing\\",
+                       "\\\\|\\\\", "||||", "\\x", " | ", "ünïcödé | ☃", "a,b", ",", " , a ,, "];
+        for text in hostile.iter() {
+            let mut b = Bookmark::new_from_line(String::from("a123|2017-12-18T11:46:29Z|:5|https://www.example.com/|Example")).unwrap();
+            b.set_title(text);
+            b.set_custom_image(text);
+            b.set_tags(&format!("{},other", text));
+            b.add_tag(text);
+            b.rename_tag("other", &format!("{},renamed", text));
+
+            let line = b.output();
+            assert!(!line.contains('\n'));
+            assert_eq!(b, Bookmark::new_from_line(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn split_line_test() {
+        assert_eq!(vec!["a", "b|c", "d\\", "\\e", "f\n"], split_line("a|b\\|c|d\\\\|\\e|f\\n"));
+        assert_eq!(vec!["trailing\\"], split_line("trailing\\"));
+    }
+
+    #[test]
+    fn offset_round_trip_test() {
+        let line = "a123|2017-12-18T11:46:29+05:30|:5|https://www.example.com/|Example|tag1|";
+        let b = Bookmark::new_from_line(String::from(line)).unwrap();
+
+        assert_eq!(line, b.output());
+        assert_eq!(time::strptime("2017-12-18T06:16:29Z", ISO_TIME_DATE).unwrap().to_timespec(),
+                   timestamp(&b.created_at));
+    }
+
+    #[test]
+    fn new_from_input_round_trip_test() {
+        let b = Bookmark::new_from_input(String::from("https://www.example.com/"), String::from("Example"),
+                                         String::from("tag1"), String::new());
+
+        assert_eq!(b, Bookmark::new_from_line(b.output()).unwrap());
+    }
+
+    /// Text biased towards the characters the line format has to escape
+    #[derive(Clone, Debug)]
+    struct Field(String);
+
+    impl Arbitrary for Field {
+        fn arbitrary<G: Gen>(g: &mut G) -> Field {
+            const HOSTILE: &[char] = &['|', '\\', '\n', '\r', 'n', ':', ',', ' ', 'Z', '+', 'é', '☃'];
+            let len = g.next_u32() as usize % (g.size() + 1);
+            Field((0..len).map(|_| match bool::arbitrary(g) {
+                true => HOSTILE[g.next_u32() as usize % HOSTILE.len()],
+                false => char::arbitrary(g)
+            }).collect())
+        }
+    }
+
+    /// Any second from 1970 to 2106, at any whole minute offset within 14 hours
+    fn arbitrary_time<G: Gen>(g: &mut G) -> time::Tm {
+        let seconds = i64::from(g.next_u32());
+        let offset = (g.next_u32() % (28 * 60 + 1)) as i32 * 60 - 14 * 3600;
+        let mut tm = time::at_utc(time::Timespec::new(seconds + i64::from(offset), 0));
+        tm.tm_utcoff = offset;
+        // strptime leaves these unset
+        tm.tm_wday = 0;
+        tm.tm_yday = 0;
+        tm
+    }
+
+    impl Arbitrary for Bookmark {
+        fn arbitrary<G: Gen>(g: &mut G) -> Bookmark {
+            let created_at = arbitrary_time(g);
+            let tags: Vec<String> = (0..g.next_u32() % 5).map(|_| Field::arbitrary(g).0).collect();
+            let mut b = Bookmark{
+                hash: Field::arbitrary(g).0,
+                created_at,
+                label: Field::arbitrary(g).0,
+                url: Field::arbitrary(g).0,
+                title: Field::arbitrary(g).0,
+                tags: parse_tags(&tags.join(",")),
+                custom_image: Field::arbitrary(g).0,
+                metadata: Metadata::default(),
+                last_visited: None,
+                visit_count: 0};
+            if bool::arbitrary(g) {
+                let feeds: Vec<String> = (0..g.next_u32() % 3).map(|_| Field::arbitrary(g).0).collect();
+                b.set_metadata(Metadata{
+                    description: Field::arbitrary(g).0,
+                    site_name: Field::arbitrary(g).0,
+                    canonical: Field::arbitrary(g).0,
+                    lang: Field::arbitrary(g).0,
+                    feeds});
+            }
+            if bool::arbitrary(g) {
+                b.last_visited = Some(arbitrary_time(g));
+                b.visit_count = u32::arbitrary(g);
+            }
+            b
+        }
+    }
+
+    quickcheck! {
+        fn bookmark_round_trip_prop(b: Bookmark) -> bool {
+            let line = b.output();
+            let parsed = Bookmark::new_from_line(line.clone()).unwrap();
+            parsed == b && parsed.output() == line
+        }
+
+        fn file_round_trip_prop(bookmarks: Vec<Bookmark>) -> bool {
+            let file: String = bookmarks.iter().map(|b| b.output() + "\n").collect();
+            let parsed: Vec<Bookmark> = file.lines()
+                .map(|l| Bookmark::new_from_line(String::from(l)).unwrap())
+                .collect();
+            let rewritten: String = parsed.iter().map(|b| b.output() + "\n").collect();
+            let reparsed: Vec<Bookmark> = rewritten.lines()
+                .map(|l| Bookmark::new_from_line(String::from(l)).unwrap())
+                .collect();
+
+            parsed == bookmarks && rewritten == file && reparsed == parsed
+        }
+    }
+
+    #[test]
+    fn bad_date_test() {
+        let line = String::from("a123|yesterday|:5|https://www.example.com/|Example|tag1,tag2|test");
+
+        match Bookmark::new_from_line(line) {
+            Err(Error::Parse(_)) => (),
+            other => panic!("Expected a parse error, got {:?}", other)
+        }
     }
 
     #[test]
@@ -8,156 +8,742 @@ extern crate url;
 extern crate chan;
 extern crate serde_json;
 
-use clap::{App, Arg, SubCommand};
-use std::io;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::io::BufRead;
-use std::fs::File;
-use std::fs::OpenOptions;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::env;
+use std::fs;
+use std::panic;
+use std::path::Path;
+use std::process;
+use std::collections::{HashMap, HashSet};
 
-use rbmlib::Bookmark;
+use rbmlib::{Bookmark, Error, Order, Result};
+use rbmlib::config::{self, Config};
+use rbmlib::export;
+use rbmlib::files::{write_atomic, Lock};
+use rbmlib::import::{self, Export};
+use rbmlib::search::Query;
+use rbmlib::store::{BookmarkStore, FileStore};
+use rbmlib::urls::UrlRules;
+#[cfg(feature = "sqlite")]
+use rbmlib::sqlite::SqliteStore;
 
 use std::thread;
 
 static NTHREADS: i32 = 10;
 
+// `add` takes the lock itself, once it has fetched the page
+const CHANGING_COMMANDS: &[&str] = &["open", "rm", "edit", "tags", "relabel", "dedupe", "import"];
+
 mod icon;
+mod serve;
+
+fn is_sqlite(path: &str) -> bool {
+    path.ends_with(".sqlite")
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &str) -> Result<Box<dyn BookmarkStore>> {
+    Ok(Box::new(SqliteStore::open(path)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(path: &str) -> Result<Box<dyn BookmarkStore>> {
+    Err(Error::Config(format!("{} needs rbm built with the sqlite feature", path)))
+}
+
+/// Picks the store from the file extension: `.sqlite` or bm.lnk format
+fn open_store(path: &str) -> Result<Box<dyn BookmarkStore>> {
+    if is_sqlite(path) {
+        open_sqlite(path)
+    } else {
+        Ok(Box::new(FileStore::new(path)))
+    }
+}
+
+/// bm.sqlite once it has been migrated to, bm.lnk otherwise
+fn default_file_path(base: &str) -> String {
+    let sqlite_path = format!("{}/bm.sqlite", base);
+    if cfg!(feature = "sqlite") && Path::new(&sqlite_path).exists() {
+        sqlite_path
+    } else {
+        format!("{}/bm.lnk", base)
+    }
+}
+
+/// Line numbers in a bm.lnk file that don't parse as bookmarks
+fn unreadable_lines(path: &str) -> Result<Vec<usize>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents.lines().enumerate()
+       .filter(|&(_, line)| !line.trim().is_empty() && Bookmark::new_from_line(line.to_string()).is_err())
+       .map(|(i, _)| i + 1)
+       .collect())
+}
 
-fn list_bookmarks(path: &str) -> Result<(), io::Error>{
-    let f = try!(File::open(path));
-    let file = BufReader::new(&f);
-    for line in file.lines() {
-        match Bookmark::new_from_line(line?){
-            Ok(b) => println!("{}", b),
-            Err(_) => continue
+/// Copies every bookmark into a new store of the other format and moves the
+/// old one aside to `.bak`, so the new store is used from then on
+fn migrate(from: &str, format: &str, output: Option<&str>) -> Result<()> {
+    let extension = match format {
+        "sqlite" => "sqlite",
+        "lnk" => "lnk",
+        _ => return Err(Error::Config(format!("Unknown store format {}", format)))
+    };
+    let to = match output {
+        Some(path) => path.to_string(),
+        None => Path::new(from).with_extension(extension).to_string_lossy().into_owned()
+    };
+    if is_sqlite(from) == is_sqlite(&to) {
+        return Err(Error::Config(format!("{} is already in {} format", from, format)));
+    }
+    if Path::new(&to).exists() {
+        return Err(Error::Config(format!("{} already exists", to)));
+    }
+
+    let _lock = Lock::acquire(from)?;
+    if !is_sqlite(from) {
+        let skipped = unreadable_lines(from)?;
+        if !skipped.is_empty() {
+            let numbers: Vec<String> = skipped.iter().map(|n| n.to_string()).collect();
+            return Err(Error::Config(format!("{} has {} unreadable lines ({}), fix or remove them before migrating",
+                                             from, skipped.len(), numbers.join(", "))));
         }
     }
+    let bookmarks = open_store(from)?.load()?;
+    let mut dest = open_store(&to)?;
+    dest.insert_all(&bookmarks)?;
+    if dest.load()? != bookmarks {
+        return Err(Error::Config(format!("{} doesn't match {} after copying", to, from)));
+    }
+
+    let backup = format!("{}.bak", from);
+    fs::rename(from, &backup)?;
+    println!("Migrated {} bookmarks to {}, old store kept as {}", bookmarks.len(), to, backup);
     Ok(())
 }
 
-fn add_bookmark(path: &str, url: &str, title: &str, tags: &str, custom_image: &str) -> Result<(), reqwest::Error>{
-    let f = OpenOptions::new()
-        .append(true)
-        .open(path)
-        .unwrap();
+fn list_bookmarks(store: &dyn BookmarkStore, long: bool, order: Order, format: &str,
+                  template: Option<&str>) -> Result<()>{
+    let mut bookmarks = store.load()?;
+    order.sort(&mut bookmarks);
+    if let Some(template) = template {
+        let template = export::Template::parse(template)?;
+        for b in &bookmarks {
+            println!("{}", template.render(b));
+        }
+        return Ok(());
+    }
+    match format {
+        "json" => println!("{}", export::json(&bookmarks)),
+        "jsonl" => print!("{}", export::json_lines(&bookmarks)),
+        "csv" => print!("{}", export::csv(&bookmarks)),
+        "tsv" => print!("{}", export::tsv(&bookmarks)),
+        _ => for b in bookmarks {
+            if long {
+                println!("{}{}", b, b.details());
+            } else {
+                println!("{}", b);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn search_bookmarks(store: &dyn BookmarkStore, query: &str) -> Result<()>{
+    let query = Query::parse(query)?;
+
+    for b in store.iter()?.filter(|b| query.matches(b)) {
+        println!("{}", b);
+    }
+    Ok(())
+}
+
+/// What `add` does when the link is already saved
+#[derive(PartialEq, Clone, Copy)]
+enum OnDuplicate {
+    Refuse,
+    /// Merge the given title, tags and image into the saved bookmark
+    Update,
+    /// Save it again anyway
+    Force,
+}
+
+struct AddOptions<'a> {
+    /// A label to use instead of one generated from the hash
+    label: Option<&'a str>,
+    on_duplicate: OnDuplicate,
+    /// Fetch the page for its metadata and icon, and its title when none is
+    /// given
+    fetch_page: bool,
+}
+
+fn add_bookmark(store: &mut dyn BookmarkStore, url: &str, title: Option<&str>, tags: Option<&str>,
+                custom_image: &str, options: &AddOptions, rules: &UrlRules) -> Result<()>{
+    let mut b = Bookmark::new_from_input_with(String::from(url), String::from(title.unwrap_or("")),
+                                              String::from(tags.unwrap_or("default")), String::from(custom_image),
+                                              rules);
+    let on_duplicate = options.on_duplicate;
+    // Fetched before locking, so a slow page doesn't hold up other rbm runs
+    let page = if options.fetch_page { icon::fetch_page(url) } else { icon::PageInfo::default() };
+
+    store.lock()?;
+    if on_duplicate != OnDuplicate::Force {
+        if let Some(existing) = store.iter()?.find(|e| e.is_duplicate_of(&b, rules)) {
+            println!("{}", existing);
+            if on_duplicate == OnDuplicate::Refuse {
+                println!("Use --update to merge into it or --force to add it again");
+                return Err(Error::Duplicate(existing.label));
+            }
+
+            let mut merged = existing.clone();
+            for tag in rbmlib::parse_tags(tags.unwrap_or("")) {
+                merged.add_tag(&tag);
+            }
+            if let Some(title) = title {
+                merged.set_title(title);
+            }
+            if !custom_image.is_empty() {
+                merged.set_custom_image(custom_image);
+            }
+            store.update(&existing, &merged)?;
+            store.unlock();
+            println!("Updated: {}", merged.title);
+            return Ok(());
+        }
+    }
+
+    let labels: Vec<String> = store.iter()?.map(|e| e.label).collect();
+    match options.label {
+        Some(label) => {
+            b.set_label(label)?;
+            if labels.contains(&b.label) {
+                return Err(Error::LabelTaken(b.label));
+            }
+        },
+        None => b.label = rbmlib::unique_label(&b.hash, &labels)
+    }
+
+    if title.is_none() {
+        b.set_title(&page.title.unwrap_or_else(|| icon::host_title(url)));
+    }
+    b.set_metadata(page.metadata);
 
-    let b = Bookmark::new_from_input(String::from(url), String::from(title), String::from(tags), String::from(custom_image));
-    let c = b.output();
-    writeln!(&f, "{}", c).unwrap();
-    let fs_path = image_path(&b.hash);
+    store.insert(&b)?;
+    store.unlock();
+    let fs_path = image_path(&b.hash)?;
     println!("{}", fs_path);
-    update_image(url, &fs_path)
+    // Without the page there is no icon, `rbm image -l` can fetch it later
+    let saved = icon::save_icon(page.icon.as_deref(), url, &fs_path);
+    // The bookmark is saved either way, so a missing image is only a warning
+    if let Err(e) = saved {
+        println!("{}", e);
+        println!("Error fetching the image for {}", b.title);
+    }
+    record_image(store, &b.hash)
+}
+
+#[cfg(feature = "sqlite")]
+fn read_firefox(path: &str) -> Result<Export> {
+    import::firefox::parse(path)
 }
 
-fn image_path(hash: &str) -> String{
-    let image_path = env::var("RBM_BASE").expect("Set RBM_BASE env");
+#[cfg(not(feature = "sqlite"))]
+fn read_firefox(path: &str) -> Result<Export> {
+    Err(Error::Config(format!("{} needs rbm built with the sqlite feature", path)))
+}
 
-    format!("{}/.bm.shots/{}.png", &image_path, hash)
+fn read_export(format: &str, path: &str, columns: &str) -> Result<Export> {
+    match format {
+        "netscape" => Ok(import::netscape::parse(&fs::read_to_string(path)?)),
+        "chrome" => import::chrome::parse(&fs::read_to_string(path)?),
+        "firefox" => read_firefox(path),
+        "pinboard" => import::pinboard::parse(&fs::read_to_string(path)?),
+        "pocket" => Ok(import::pocket::parse(&fs::read_to_string(path)?)),
+        "csv" => {
+            let columns = import::csv::columns(columns)?;
+            Ok(import::csv::parse(&fs::read_to_string(path)?, &columns))
+        },
+        _ => Err(Error::Config(format!("Can't import {} files", format)))
+    }
 }
 
-fn output_html(path: &str) -> Result<(), io::Error>{
-    let mut bs: Vec<Bookmark> = Vec::new();
-    
-    let directory_path = env::var("RBM_BASE").expect("Set RBM_BASE env");
+struct ImportOptions {
+    /// Keep the visits the browser counted, so frecency has a starting point
+    visits: bool,
+    /// Work out what would be added without adding it
+    dry_run: bool,
+}
 
-    let directory_path = format!("{}/bm.html", directory_path);
+/// Adds bookmarks read from an export the way `add` does. Entries already
+/// saved, or seen earlier in the export, are skipped. Returns the bookmarks
+/// added and how many were skipped.
+fn import_bookmarks(store: &mut dyn BookmarkStore, export: Export, options: &ImportOptions,
+                    rules: &UrlRules) -> Result<(Vec<Bookmark>, usize)> {
+    let existing = store.load()?;
+    let mut labels: Vec<String> = existing.iter().map(|e| e.label.clone()).collect();
+    // What `is_duplicate_of` compares, worked out once for every bookmark
+    let mut hashes: HashSet<String> = existing.iter().map(|e| e.hash.clone()).collect();
+    let mut urls: HashSet<String> = existing.iter().map(|e| rules.normalize(&e.url)).collect();
+    let mut added: Vec<Bookmark> = Vec::new();
+    let mut skipped = 0;
 
-    let f = try!(File::open(path));
-    let file = BufReader::new(&f);
-    for line in file.lines(){
-        match Bookmark::new_from_line(line?){
-            Ok(b) => bs.push(b),
-            Err(_) => continue
-        };
+    for entry in export.entries {
+        let tags = if entry.tags.is_empty() { String::from("default") } else { entry.tags.join(",") };
+        let title = if entry.title.is_empty() { icon::host_title(&entry.url) } else { entry.title };
+        let mut b = Bookmark::new_from_input_with(entry.url, title, tags, String::new(), rules);
+        let url = rules.normalize(&b.url);
+        if hashes.contains(&b.hash) || urls.contains(&url) {
+            skipped += 1;
+            continue;
+        }
+        hashes.insert(b.hash.clone());
+        urls.insert(url);
+        if let Some(created_at) = entry.created_at {
+            b.set_created_at(created_at);
+        }
+        if options.visits {
+            b.set_visits(entry.visit_count, entry.last_visited);
+        }
+        b.label = rbmlib::unique_label(&b.hash, &labels);
+        labels.push(b.label.clone());
+        added.push(b);
     }
-    
-    let fo = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(directory_path)
-        .unwrap();
-    
-    let a = rbmlib::html_output(bs);
-    write!(&fo, "{}", a)
+
+    if !options.dry_run {
+        store.insert_all(&added)?;
+    }
+    Ok((added, skipped))
 }
 
-fn update_image(path: &str, fs_path: &str) -> Result<(), reqwest::Error>{
-    icon::download_image(path, fs_path)
+/// The browser to open urls with, split into the program and its
+/// arguments: the configured one, else the first in `$BROWSER`, else xdg-open
+fn browser_command(config: &Config) -> Vec<String> {
+    let from_env = env::var("BROWSER").ok()
+        .and_then(|browsers| browsers.split(':').next().map(config::split_command));
+    let command = config.browser.clone().or(from_env).unwrap_or_default();
+    if command.is_empty() {
+        vec![String::from("xdg-open")]
+    } else {
+        command
+    }
+}
+
+/// Starts the browser without waiting for it. The url replaces any `%s`
+/// argument, or goes after the others.
+fn launch(command: &[String], url: &str) -> Result<()> {
+    let (program, args) = command.split_first()
+        .ok_or_else(|| Error::Config(String::from("Empty browser command")))?;
+    let mut args: Vec<String> = args.iter().map(|a| a.replace("%s", url)).collect();
+    if !command.iter().any(|a| a.contains("%s")) {
+        args.push(url.to_string());
+    }
+    process::Command::new(program).args(&args).spawn()
+        .map_err(|e| Error::Config(format!("Unable to run {}: {}", program, e)))?;
+    Ok(())
+}
+
+/// Bookmarks with the label, hash or url, or failing that the ones the
+/// search query matches
+fn find_or_search(store: &dyn BookmarkStore, query: &str, rules: &UrlRules) -> Result<Vec<Bookmark>> {
+    let found = store.find(query, rules)?;
+    if !found.is_empty() || query.trim().is_empty() {
+        return Ok(found);
+    }
+    match Query::parse(query) {
+        Ok(search) => Ok(store.iter()?.filter(|b| search.matches(b)).collect()),
+        Err(_) => Ok(found)
+    }
+}
+
+/// Opens the bookmark matching the query, or every bookmark with the tag,
+/// and records the visits
+fn open_bookmarks(store: &mut dyn BookmarkStore, query: Option<&str>, tag: Option<&str>, rules: &UrlRules,
+                  browser: &[String]) -> Result<()> {
+    let opened: Vec<Bookmark> = match tag {
+        Some(tag) => store.iter()?.filter(|b| b.has_tag(tag)).collect(),
+        None => find_or_search(store, query.unwrap_or_default(), rules)?
+    };
+    let wanted = tag.map(|t| format!("tag {}", t)).or_else(|| query.map(String::from)).unwrap_or_default();
+
+    if opened.is_empty() {
+        return Err(Error::NotFound(wanted));
+    }
+    if opened.len() > 1 && tag.is_none() {
+        for b in &opened {
+            println!("{}", b);
+        }
+        return Err(Error::Ambiguous(wanted, opened.len()));
+    }
+
+    let mut visits: Vec<(Bookmark, Bookmark)> = Vec::new();
+    for b in opened {
+        // Keep the visits to the ones already opened if the browser fails
+        if let Err(e) = launch(browser, &b.url) {
+            store.update_all(&visits)?;
+            return Err(e);
+        }
+        let mut visited = b.clone();
+        visited.visit();
+        visits.push((b, visited));
+    }
+    store.update_all(&visits)
+}
+
+/// True if a stored bookmark has the hash. Copies of a link saved with
+/// --force share its hash, and so its images.
+fn hash_still_used(store: &dyn BookmarkStore, hash: &str) -> Result<bool>{
+    Ok(store.iter()?.any(|b| b.hash == hash))
+}
+
+fn remove_images(store: &mut dyn BookmarkStore, hash: &str) -> Result<()>{
+    for p in image_paths(hash)?.iter() {
+        if Path::new(p).exists() {
+            fs::remove_file(p)?;
+            println!("Removed {}", p);
+        }
+    }
+    store.record_image(hash, None)
+}
+
+/// Tells the store which downloaded image the hash has now
+fn record_image(store: &mut dyn BookmarkStore, hash: &str) -> Result<()>{
+    let saved = image_paths(hash)?.iter()
+        .find(|p| Path::new(p).exists())
+        .and_then(|p| Path::new(p).extension().and_then(|e| e.to_str()).map(String::from));
+    store.record_image(hash, saved.as_deref())
+}
+
+fn remove_bookmarks(store: &mut dyn BookmarkStore, query: &str, all: bool, rules: &UrlRules) -> Result<()>{
+    let removed = store.find(query, rules)?;
+
+    if removed.is_empty() {
+        return Err(Error::NotFound(query.to_string()));
+    }
+
+    if removed.len() > 1 && !all {
+        for b in &removed {
+            println!("{}", b);
+        }
+        println!("Use --all to remove them all");
+        return Err(Error::Ambiguous(query.to_string(), removed.len()));
+    }
+
+    for b in &removed {
+        store.delete(b)?;
+        println!("Removed: {}", b.title);
+    }
+    for b in &removed {
+        if !hash_still_used(store, &b.hash)? {
+            remove_images(store, &b.hash)?;
+        }
+    }
+    Ok(())
+}
+
+/// Folds later copies of a link into the first, which keeps its label and
+/// title and gains their tags. Returns how many copies were removed
+fn dedupe(store: &mut dyn BookmarkStore, rules: &UrlRules) -> Result<usize> {
+    let mut kept: Vec<(Bookmark, Bookmark)> = Vec::new();
+    let mut removed: Vec<Bookmark> = Vec::new();
+    // What `is_duplicate_of` compares, mapped to the position in `kept`
+    let mut hashes: HashMap<String, usize> = HashMap::new();
+    let mut urls: HashMap<String, usize> = HashMap::new();
+
+    for b in store.iter()? {
+        let url = rules.normalize(&b.url);
+        match hashes.get(&b.hash).or_else(|| urls.get(&url)) {
+            Some(&i) => {
+                kept[i].1.merge(&b);
+                removed.push(b);
+            },
+            None => {
+                hashes.insert(b.hash.clone(), kept.len());
+                urls.insert(url, kept.len());
+                kept.push((b.clone(), b));
+            }
+        }
+    }
+
+    let changes: Vec<(Bookmark, Bookmark)> = kept.into_iter()
+        .filter(|(stored, merged)| stored != merged)
+        .collect();
+    store.update_all(&changes)?;
+    for b in &removed {
+        store.delete(b)?;
+        println!("Removed duplicate :{} {}", b.label, b.url);
+    }
+
+    let remaining: Vec<String> = store.iter()?.map(|b| b.hash).collect();
+    for b in &removed {
+        if !remaining.contains(&b.hash) {
+            remove_images(store, &b.hash)?;
+        }
+    }
+    Ok(removed.len())
+}
+
+/// Gives the new hash the old hash's images, copying them while other
+/// bookmarks still use the old hash. Images already saved for the new hash
+/// are kept instead.
+fn move_images(old_hash: &str, new_hash: &str, shared: bool) -> Result<()>{
+    let has_image = image_paths(new_hash)?.iter().any(|p| Path::new(p).exists());
+    for (from, to) in image_paths(old_hash)?.iter().zip(image_paths(new_hash)?.iter()) {
+        if !Path::new(from).exists() || (has_image && shared) {
+            continue
+        }
+        if has_image {
+            fs::remove_file(from)?;
+        } else if shared {
+            fs::copy(from, to)?;
+        } else {
+            fs::rename(from, to)?;
+        }
+    }
+    Ok(())
+}
+
+fn edit_bookmark(store: &mut dyn BookmarkStore, label: &str, changes: &ArgMatches, rules: &UrlRules) -> Result<()>{
+    let found = store.find(label, rules)?;
+
+    let old = match found.len() {
+        0 => return Err(Error::NotFound(label.to_string())),
+        1 => found.into_iter().next().unwrap(),
+        n => return Err(Error::Ambiguous(label.to_string(), n))
+    };
+    let mut b = old.clone();
+
+    if let Some(title) = changes.value_of("title") {
+        b.set_title(title);
+    }
+    if let Some(tags) = changes.value_of("tags") {
+        b.set_tags(tags);
+    }
+    if let Some(tags) = changes.values_of("add_tag") {
+        for tag in tags {
+            b.add_tag(tag);
+        }
+    }
+    if let Some(tags) = changes.values_of("remove_tag") {
+        for tag in tags {
+            b.remove_tag(tag);
+        }
+    }
+    let others: Vec<String> = store.iter()?.filter(|o| *o != old).map(|o| o.label).collect();
+    if let Some(url) = changes.value_of("url") {
+        b.set_url(url, rules);
+        if b.has_generated_label() {
+            b.label = rbmlib::unique_label(&b.hash, &others);
+        }
+    }
+    if let Some(label) = changes.value_of("new_label") {
+        b.set_label(label)?;
+        if others.contains(&b.label) {
+            return Err(Error::LabelTaken(b.label));
+        }
+    }
+    if let Some(custom_image) = changes.value_of("custom_image") {
+        b.set_custom_image(custom_image);
+    }
+
+    store.update(&old, &b)?;
+
+    if b.hash != old.hash {
+        let shared = hash_still_used(store, &old.hash)?;
+        move_images(&old.hash, &b.hash, shared)?;
+        record_image(store, &old.hash)?;
+        record_image(store, &b.hash)?;
+    }
+    println!("{}", b);
+    Ok(())
+}
+
+/// Applies `f` to every bookmark and saves the ones it changed in one go.
+/// Returns the number of bookmarks changed.
+fn rewrite_bookmarks<F>(store: &mut dyn BookmarkStore, mut f: F) -> Result<usize>
+    where F: FnMut(&mut Bookmark) -> bool {
+    let mut changes: Vec<(Bookmark, Bookmark)> = Vec::new();
+
+    for old in store.iter()? {
+        let mut b = old.clone();
+        if f(&mut b) {
+            changes.push((old, b));
+        }
+    }
+
+    if !changes.is_empty() {
+        store.update_all(&changes)?;
+    }
+    Ok(changes.len())
+}
+
+/// Gives bookmarks whose generated label is also another bookmark's label a
+/// longer prefix of their hash. Chosen labels and the first bookmark with each
+/// generated label are kept. Returns the number of bookmarks relabelled.
+fn relabel(store: &mut dyn BookmarkStore) -> Result<usize> {
+    let mut taken: Vec<String> = store.iter()?
+        .filter(|b| !b.has_generated_label())
+        .map(|b| b.label)
+        .collect();
+
+    rewrite_bookmarks(store, |b| {
+        if !b.has_generated_label() {
+            return false
+        }
+        let relabelled = taken.contains(&b.label);
+        if relabelled {
+            let label = rbmlib::unique_label(&b.hash, &taken);
+            println!("Relabelled :{} as :{}", b.label, label);
+            b.label = label;
+        }
+        taken.push(b.label.clone());
+        relabelled
+    })
+}
+
+fn list_tags(store: &dyn BookmarkStore) -> Result<()>{
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for b in store.iter()? {
+        for tag in b.tags() {
+            *counts.entry(tag.clone()).or_insert(0) += 1;
+        }
+    }
+
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    for (tag, count) in counts {
+        println!("{:>5} {}", count, tag);
+    }
+    Ok(())
+}
+
+fn rename_tag(store: &mut dyn BookmarkStore, from: &str, to: &str) -> Result<usize>{
+    rewrite_bookmarks(store, |b| b.rename_tag(from, to))
+}
+
+fn merge_tags(store: &mut dyn BookmarkStore, from: &[&str], into: &str) -> Result<usize>{
+    let into_tag = rbmlib::normalize_tag(into);
+    rewrite_bookmarks(store, |b| {
+        let mut changed = false;
+        for tag in from.iter().filter(|t| rbmlib::normalize_tag(t) != into_tag) {
+            changed |= b.rename_tag(tag, into);
+        }
+        changed
+    })
+}
+
+fn delete_tag(store: &mut dyn BookmarkStore, tag: &str) -> Result<usize>{
+    rewrite_bookmarks(store, |b| b.remove_tag(tag))
 }
 
-fn refresh_all_images(path: &str) -> Result<(), io::Error>{
-    let r = {
-        let (s, r) = chan::sync(0);
-
-        let f = try!(File::open(path));
-        thread::spawn(move || 
-                      {
-                          let file = BufReader::new(&f);
-                          for line in file.lines() {
-                              let l = line.unwrap();
-                              let b = match Bookmark::new_from_line(l){
-                                  Ok(b) => b,
-                                  Err(_) => continue
-                              };
-                              s.send(b);
-                          }
-                      });
-        r
+fn image_path(hash: &str) -> Result<String>{
+    let image_path = rbmlib::base_dir()?;
+
+    Ok(format!("{}/.bm.shots/{}.png", &image_path, hash))
+}
+
+/// Where the downloaded image for the hash may be, as png or svg
+fn image_paths(hash: &str) -> Result<[String; 2]>{
+    let shots = format!("{}/.bm.shots", rbmlib::base_dir()?);
+    Ok([format!("{}/{}.png", shots, hash), format!("{}/{}.svg", shots, hash)])
+}
+
+fn output_html(store: &dyn BookmarkStore, config: &Config) -> Result<()>{
+    let directory_path = rbmlib::base_dir()?;
+
+    let directory_path = format!("{}/bm.html", directory_path);
+
+    let mut bookmarks = store.load()?;
+    config.html_order.sort(&mut bookmarks);
+    let redirect = match config.redirect {
+        Some(ref redirect) => Some(format!("{}{}/", redirect, serve::visit_key()?)),
+        None => None
     };
+    let a = rbmlib::html_output_via(bookmarks, redirect.as_deref())?;
+
+    write_atomic(directory_path, a.as_bytes())
+}
+
+fn update_image(path: &str, fs_path: &str) -> Result<()>{
+    icon::download_image(path, fs_path)
+}
+
+fn refresh_all_images(store: &mut dyn BookmarkStore) -> Result<()>{
+    let bookmarks = store.load()?;
+    fetch_images(store, bookmarks)
+}
+
+/// Marks a worker done when it is dropped, so `wait` returns even if the
+/// worker panics
+struct Done(chan::WaitGroup);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+/// Downloads images for the bookmarks without a custom one, a few at a time
+fn fetch_images(store: &mut dyn BookmarkStore, bookmarks: Vec<Bookmark>) -> Result<()> {
+    let hashes: HashSet<String> = bookmarks.iter().map(|b| b.hash.clone()).collect();
+    let (s, r) = chan::sync::<Bookmark>(0);
 
     let wg = chan::WaitGroup::new();
     for _ in 0..NTHREADS {
         // The `recv` method picks a message from the channel
         // `recv` will block the current thread if there are no messages available
         wg.add(1);
-        let wg = wg.clone();
+        let done = Done(wg.clone());
         let r = r.clone();
         thread::spawn(move || {
+            let _done = done;
             for bm in r{
                 if bm.custom_image.len() > 0 {
                     continue
                 }
-                match update_image(&bm.url, &image_path(&bm.hash)){
-                    Ok(_) => println!("Updated: {}", &bm.title),
-                    Err(e) => {
+                // A page that panics the icon code only loses its own image
+                let updated = panic::catch_unwind(|| image_path(&bm.hash).and_then(|p| update_image(&bm.url, &p)));
+                match updated {
+                    Ok(Ok(_)) => println!("Updated: {}", &bm.title),
+                    Ok(Err(e)) => {
                         println!("{:?}", e);
                         println!("Error updating {}", &bm.title)
-                    }
+                    },
+                    Err(_) => println!("Error updating {}", &bm.title)
                 };   
             }
-            wg.done();
         });
 
     }
+
+    for b in bookmarks {
+        s.send(b);
+    }
+    drop(s);
+
     wg.wait();
-        Ok(())
+    for hash in &hashes {
+        record_image(store, hash)?;
+    }
+    Ok(())
 }
 
-fn refresh_image(path: &str, label: &str) -> Result<(), io::Error>{
+fn refresh_image(store: &mut dyn BookmarkStore, label: &str, rules: &UrlRules) -> Result<()>{
     // refresh the iage for an existing bookmark
-
-    let f = try!(File::open(path));
-    let file = BufReader::new(&f);
-    for line in file.lines() {
-        let b = match Bookmark::new_from_line(line?){
-            Ok(b) => b,
-            Err(_) => continue
-        };
-        if b.label == label{
-            match update_image(&b.url, &image_path(&b.hash)){
-                Ok(_) => return Ok(()),
-                Err(_) => println!("Unable to refresh image")
-            };
+    let found = store.find(label, rules)?;
+    let b = match found.len() {
+        0 => return Err(Error::NotFound(label.to_string())),
+        1 => &found[0],
+        n => {
+            for b in &found {
+                println!("{}", b);
+            }
+            return Err(Error::Ambiguous(label.to_string(), n));
         }
-    }
-    // TODO: This should be an error, not OK
-    Ok(())
+    };
+    let updated = update_image(&b.url, &image_path(&b.hash)?);
+    record_image(store, &b.hash)?;
+    updated
 }
 
-fn main() {
+fn run() -> Result<()> {
     let matches = App::new("Bookmark Manager")
         .version("1.0")
         .author("Daniel Bowman")
@@ -167,19 +753,49 @@ fn main() {
              .value_name("FILE")
              .help("Location of bookmarks file")
              .takes_value(true))
-        .subcommand(SubCommand::with_name("list"))
+        .subcommand(SubCommand::with_name("list")
+                    .arg(Arg::with_name("long")
+                         .short("l")
+                         .long("long")
+                         .help("Show the description, site name, canonical link, language, feeds and visits"))
+                    .arg(Arg::with_name("sort")
+                         .short("s")
+                         .long("sort")
+                         .value_name("ORDER")
+                         .possible_values(&["file", "frecency"])
+                         .default_value("file")
+                         .help("frecency lists the most used first"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .possible_values(&["text", "json", "jsonl", "csv", "tsv"])
+                         .default_value("text")
+                         .help("jsonl writes a JSON object per line"))
+                    .arg(Arg::with_name("template")
+                         .long("template")
+                         .value_name("TEMPLATE")
+                         .help("Print each bookmark as e.g. '{label}\\t{url}', with any field of the json output")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("search")
+                    .about("Search bookmarks, e.g. 'tag:rust -tag:old site:github.com title:\"async\" after:2018-01-01 words'")
+                    .setting(AppSettings::AllowLeadingHyphen)
+                    .arg(Arg::with_name("query")
+                         .value_name("QUERY")
+                         .multiple(true)
+                         .allow_hyphen_values(true)))
         .subcommand(SubCommand::with_name("add")
                     .arg(Arg::with_name("url")
                          .short("u")
                          .long("url")
                          .value_name("URL")
                          .help("Url to add")
+                         .required(true)
                          .takes_value(true))
                     .arg(Arg::with_name("title")
                          .short("T")
                          .long("title")
                          .value_name("TITLE")
-                         .help("URL title")
+                         .help("URL title, fetched from the page if not given")
                          .takes_value(true))
                     .arg(Arg::with_name("taglist")
                          .short("t")
@@ -192,8 +808,189 @@ fn main() {
                          .long("custom_image")
                          .value_name("CUSTOM_IMAGE")
                          .help("custom_image")
+                         .takes_value(true))
+                    .arg(Arg::with_name("label")
+                         .short("l")
+                         .long("label")
+                         .value_name("LABEL")
+                         .help("Label to use instead of the start of the hash")
+                         .takes_value(true))
+                    .arg(Arg::with_name("update")
+                         .long("update")
+                         .help("Merge the title and tags into the bookmark if the link is already saved")
+                         .conflicts_with("force"))
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .help("Add the link even if it is already saved"))
+                    .arg(Arg::with_name("no_fetch")
+                         .long("no-fetch")
+                         .help("Don't fetch the page for its title, description or image, use the hostname as the title")))
+        .subcommand(SubCommand::with_name("open")
+                    .about("Open a bookmark, or all bookmarks with a tag, in the browser")
+                    .arg(Arg::with_name("query")
+                         .value_name("LABEL")
+                         .help("Label, hash or url, else a search query that must match one bookmark")
+                         .required_unless("tag")
+                         .conflicts_with("tag")
+                         .takes_value(true))
+                    .arg(Arg::with_name("tag")
+                         .short("t")
+                         .long("tag")
+                         .value_name("TAG")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("rm")
+                    .about("Remove bookmarks by label, hash (or hash prefix) or URL")
+                    .arg(Arg::with_name("query")
+                         .value_name("LABEL|HASH|URL")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("all")
+                         .short("a")
+                         .long("all")
+                         .help("Remove every match when more than one bookmark matches")
+                         .takes_value(false)))
+        .subcommand(SubCommand::with_name("edit")
+                    .about("Change an existing bookmark")
+                    .arg(Arg::with_name("label")
+                         .value_name("LABEL")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("title")
+                         .short("T")
+                         .long("title")
+                         .value_name("TITLE")
+                         .help("New title")
+                         .takes_value(true))
+                    .arg(Arg::with_name("tags")
+                         .short("t")
+                         .long("tags")
+                         .value_name("TAGLIST")
+                         .help("Replace the tags")
+                         .takes_value(true))
+                    .arg(Arg::with_name("add_tag")
+                         .long("add-tag")
+                         .value_name("TAG")
+                         .help("Tag to add")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("remove_tag")
+                         .long("remove-tag")
+                         .value_name("TAG")
+                         .help("Tag to remove")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("url")
+                         .short("u")
+                         .long("url")
+                         .value_name("URL")
+                         .help("New url")
+                         .takes_value(true))
+                    .arg(Arg::with_name("new_label")
+                         .short("l")
+                         .long("label")
+                         .value_name("LABEL")
+                         .help("New label")
+                         .takes_value(true))
+                    .arg(Arg::with_name("custom_image")
+                         .short("c")
+                         .long("custom-image")
+                         .value_name("CUSTOM_IMAGE")
+                         .help("custom_image")
                          .takes_value(true)))
+        .subcommand(SubCommand::with_name("tags")
+                    .about("List tags with their counts, or rename, merge and delete them")
+                    .subcommand(SubCommand::with_name("rename")
+                                .arg(Arg::with_name("old")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("new")
+                                     .required(true)
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("merge")
+                                .arg(Arg::with_name("tags")
+                                     .value_name("TAG")
+                                     .required(true)
+                                     .multiple(true))
+                                .arg(Arg::with_name("into")
+                                     .long("into")
+                                     .value_name("TAG")
+                                     .required(true)
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("delete")
+                                .arg(Arg::with_name("tag")
+                                     .required(true)
+                                     .takes_value(true))))
         .subcommand(SubCommand::with_name("html"))
+        .subcommand(SubCommand::with_name("serve")
+                    .about("Serve the page locally, counting visits through /go/<hash>")
+                    .arg(Arg::with_name("port")
+                         .short("p")
+                         .long("port")
+                         .value_name("PORT")
+                         .default_value("8080")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Add the bookmarks from another browser or service's export")
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .possible_values(&["netscape", "chrome", "firefox", "pinboard", "pocket", "csv"])
+                         .default_value("netscape")
+                         .help("netscape is the bookmark HTML file browsers export, chrome the Bookmarks file in a Chrome or Chromium profile, firefox a profile's places.sqlite, pinboard and pocket those services' JSON and HTML exports"))
+                    .arg(Arg::with_name("columns")
+                         .long("columns")
+                         .value_name("COLUMNS")
+                         .default_value("url,title,tags,date")
+                         .help("What each csv column holds, from url, title, tags, date and - to skip one"))
+                    .arg(Arg::with_name("dry_run")
+                         .long("dry-run")
+                         .help("Show what would be imported without changing anything"))
+                    .arg(Arg::with_name("visits")
+                         .long("visits")
+                         .help("Keep the browser's visit counts, where the export has them"))
+                    .arg(Arg::with_name("fetch_icons")
+                         .long("fetch-icons")
+                         .help("Download images for the imported bookmarks"))
+                    .arg(Arg::with_name("input")
+                         .value_name("FILE")
+                         .required(true)))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Write the bookmarks for a browser or another service to import")
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .possible_values(&["netscape"])
+                         .default_value("netscape")
+                         .help("netscape is the bookmark HTML file browsers import"))
+                    .arg(Arg::with_name("folders")
+                         .long("folders")
+                         .help("Put each bookmark in a folder named after its first tag"))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .help("Defaults to standard output")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("relabel")
+                    .about("Give bookmarks that share a generated label longer ones"))
+        .subcommand(SubCommand::with_name("dedupe")
+                    .about("Merge bookmarks saved more than once"))
+        .subcommand(SubCommand::with_name("migrate")
+                    .about("Move bookmarks to another store format")
+                    .arg(Arg::with_name("to")
+                         .long("to")
+                         .value_name("FORMAT")
+                         .possible_values(&["sqlite", "lnk"])
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .help("Defaults to the current store with the format's extension")
+                         .takes_value(true)))
         .subcommand(SubCommand::with_name("image")
                     .arg(Arg::with_name("all")
                          .short("a")
@@ -207,38 +1004,427 @@ fn main() {
         .get_matches();
 
 
-    let file_env = env::var("RBM_BASE").expect("Set RBM_BASE env");
+    let file_env = rbmlib::base_dir()?;
     
-    let default_file_path = format!("{}/bm.lnk", file_env);
+    let default_file_path = default_file_path(&file_env);
     
     let file = matches.value_of("file").unwrap_or(&default_file_path);
+
+    if let Some(matches) = matches.subcommand_matches("migrate") {
+        return migrate(file, matches.value_of("to").unwrap(), matches.value_of("output"));
+    }
+
+    let config = Config::load()?;
+    let mut store = open_store(file)?;
+    let store = store.as_mut();
+    // Commands that change bookmarks hold the lock from their first read to
+    // their last write, so another rbm can't change the store in between
+    if matches.subcommand_name().map_or(false, |name| CHANGING_COMMANDS.contains(&name)) {
+        store.lock()?;
+    }
         
     if let Some(matches) = matches.subcommand_matches("add") {
         let url = matches.value_of("url").unwrap();
-        let title = matches.value_of("title").unwrap_or("Default");
-        let taglist = matches.value_of("taglist").unwrap_or("default");
         let custom_image = matches.value_of("custom_image").unwrap_or("");
+        let on_duplicate = if matches.is_present("update") {
+            OnDuplicate::Update
+        } else if matches.is_present("force") {
+            OnDuplicate::Force
+        } else {
+            OnDuplicate::Refuse
+        };
+        let options = AddOptions{
+            label: matches.value_of("label"),
+            on_duplicate,
+            fetch_page: !matches.is_present("no_fetch"),
+        };
 
-        add_bookmark(file, url, title, taglist, custom_image).unwrap();
-        output_html(file).unwrap();
-            
+        add_bookmark(store, url, matches.value_of("title"), matches.value_of("taglist"), custom_image, &options,
+                     &config.url_rules)?;
+        output_html(store, &config)?;
     }
     
-    if matches.subcommand_matches("list").is_some() {
-        list_bookmarks(file).unwrap();
+    if let Some(matches) = matches.subcommand_matches("open") {
+        open_bookmarks(store, matches.value_of("query"), matches.value_of("tag"), &config.url_rules,
+                       &browser_command(&config))?;
+        output_html(store, &config)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rm") {
+        let query = matches.value_of("query").unwrap();
+
+        remove_bookmarks(store, query, matches.is_present("all"), &config.url_rules)?;
+        output_html(store, &config)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("edit") {
+        let label = matches.value_of("label").unwrap();
+
+        edit_bookmark(store, label, matches, &config.url_rules)?;
+        output_html(store, &config)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("tags") {
+        let changed = match matches.subcommand() {
+            ("rename", Some(m)) => rename_tag(store, m.value_of("old").unwrap(), m.value_of("new").unwrap()),
+            ("merge", Some(m)) => merge_tags(store, &m.values_of("tags").unwrap().collect::<Vec<&str>>(),
+                                             m.value_of("into").unwrap()),
+            ("delete", Some(m)) => delete_tag(store, m.value_of("tag").unwrap()),
+            _ => list_tags(store).map(|_| 0)
+        }?;
+
+        if changed > 0 {
+            println!("Updated {} bookmarks", changed);
+            output_html(store, &config)?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("list") {
+        list_bookmarks(store, matches.is_present("long"), Order::parse(matches.value_of("sort").unwrap())?,
+                       matches.value_of("format").unwrap(), matches.value_of("template"))?;
+    }
+    if let Some(matches) = matches.subcommand_matches("search") {
+        let query = matches.values_of("query")
+            .map(|v| v.collect::<Vec<&str>>().join(" "))
+            .unwrap_or_default();
+
+        search_bookmarks(store, &query)?;
+    }
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let input = matches.value_of("input").unwrap();
+        let export = read_export(matches.value_of("format").unwrap(), input, matches.value_of("columns").unwrap())?;
+        for reason in &export.rejected {
+            println!("Rejected: {}", reason);
+        }
+        let rejected = export.rejected.len();
+        let options = ImportOptions{visits: matches.is_present("visits"), dry_run: matches.is_present("dry_run")};
+        let (added, skipped) = import_bookmarks(store, export, &options, &config.url_rules)?;
+        if options.dry_run {
+            for b in &added {
+                println!("{}", b);
+            }
+            println!("Would import {}, skip {} duplicates, reject {}", added.len(), skipped, rejected);
+            return Ok(());
+        }
+        // Downloading icons can take minutes, so other rbm runs can go first
+        store.unlock();
+        println!("Imported {}, skipped {} duplicates, rejected {}", added.len(), skipped, rejected);
+        // Icons first, so the page is written with them
+        let changed = !added.is_empty();
+        if matches.is_present("fetch_icons") {
+            fetch_images(store, added)?;
+        }
+        if changed {
+            output_html(store, &config)?;
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("export") {
+        let bookmarks = store.load()?;
+        let exported = match matches.value_of("format").unwrap() {
+            "netscape" => export::netscape(&bookmarks, matches.is_present("folders")),
+            format => return Err(Error::Config(format!("Can't export {} files", format)))
+        };
+        match matches.value_of("output") {
+            Some(output) => write_atomic(output, exported.as_bytes())?,
+            None => print!("{}", exported)
+        }
+    }
+    if matches.subcommand_matches("relabel").is_some() {
+        let relabelled = relabel(store)?;
+        println!("Relabelled {} bookmarks", relabelled);
+        if relabelled > 0 {
+            output_html(store, &config)?;
+        }
+    }
+    if matches.subcommand_matches("dedupe").is_some() {
+        let removed = dedupe(store, &config.url_rules)?;
+        println!("Removed {} duplicates", removed);
+        if removed > 0 {
+            output_html(store, &config)?;
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        let port = matches.value_of("port").unwrap().parse::<u16>()
+            .map_err(|_| Error::Config(String::from("The port should be a number up to 65535")))?;
+        serve::serve(store, &config, port)?;
     }
     if matches.subcommand_matches("html").is_some() {
-        output_html(file).unwrap();
+        output_html(store, &config)?;
     }
 
     
     if let Some(matches) = matches.subcommand_matches("image") {
         match matches.values_of("all"){
-            Some(_) => refresh_all_images(file).unwrap(),
+            Some(_) => refresh_all_images(store)?,
             _ => {
                 let label = matches.value_of("label").unwrap();
-                refresh_image(file, label).unwrap();
+                refresh_image(store, label, &config.url_rules)?;
             }
         }
     }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbmlib::store::MemoryStore;
+
+    fn tagged(url: &str, tags: &str) -> Bookmark {
+        Bookmark::new_from_input(String::from(url), String::from("Example"), String::from(tags), String::new())
+    }
+
+    /// Points `RBM_BASE` at a directory for the images tests write. Every
+    /// test uses the same one, so tests running at once agree on it.
+    fn test_base() {
+        let base = env::temp_dir().join(format!("rbm-main-test-{}", process::id()));
+        fs::create_dir_all(base.join(".bm.shots")).unwrap();
+        env::set_var("RBM_BASE", &base);
+    }
+
+    fn save_image(hash: &str, contents: &str) {
+        fs::write(image_path(hash).unwrap(), contents).unwrap();
+    }
+
+    fn saved_image(hash: &str) -> Option<String> {
+        fs::read_to_string(image_path(hash).unwrap()).ok()
+    }
+
+    #[test]
+    fn remove_bookmarks_test() {
+        test_base();
+        let rules = UrlRules::default();
+        let mut first = tagged("https://example.com/rm", "rust");
+        let mut second = tagged("https://example.org/rm", "rust");
+        first.hash = String::from("f00d0001");
+        second.hash = String::from("f00d0002");
+        let third = tagged("https://example.net/rm", "rust");
+        let mut store = MemoryStore::from(vec![first, second, third.clone()]);
+
+        match remove_bookmarks(&mut store, "f00d", false, &rules) {
+            Err(Error::Ambiguous(_, 2)) => (),
+            other => panic!("expected two matches, got {:?}", other)
+        }
+        assert_eq!(3, store.load().unwrap().len());
+        remove_bookmarks(&mut store, "f00d", true, &rules).unwrap();
+        assert_eq!(vec![third.clone()], store.load().unwrap());
+
+        // The same link once normalized
+        remove_bookmarks(&mut store, "HTTPS://Example.net:443/rm?utm_source=feed", false, &rules).unwrap();
+        assert!(store.load().unwrap().is_empty());
+        match remove_bookmarks(&mut store, "https://example.net/rm", false, &rules) {
+            Err(Error::NotFound(_)) => (),
+            other => panic!("expected no match, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn remove_bookmarks_images_test() {
+        test_base();
+        let rules = UrlRules::default();
+        let first = tagged("https://example.com/rm-images", "rust");
+        let mut copy = first.clone();
+        copy.set_label("copy").unwrap();
+        save_image(&first.hash, "icon");
+        let mut store = MemoryStore::from(vec![first.clone(), copy]);
+
+        remove_bookmarks(&mut store, "copy", false, &rules).unwrap();
+        assert_eq!(Some(String::from("icon")), saved_image(&first.hash));
+        remove_bookmarks(&mut store, &first.label, false, &rules).unwrap();
+        assert_eq!(None, saved_image(&first.hash));
+    }
+
+    fn add_options(on_duplicate: OnDuplicate) -> AddOptions<'static> {
+        AddOptions{label: None, on_duplicate, fetch_page: false}
+    }
+
+    #[test]
+    fn add_bookmark_duplicate_test() {
+        test_base();
+        let rules = UrlRules::default();
+        let mut store = MemoryStore::new();
+        // Not a url rbm can fetch, so no image is downloaded
+        let url = "example.com/duplicate";
+        add_bookmark(&mut store, url, Some("Example"), Some("rust"), "", &add_options(OnDuplicate::Refuse),
+                     &rules).unwrap();
+
+        match add_bookmark(&mut store, url, Some("Again"), Some("web"), "", &add_options(OnDuplicate::Refuse),
+                           &rules) {
+            Err(Error::Duplicate(_)) => (),
+            other => panic!("expected a duplicate, got {:?}", other)
+        }
+        assert_eq!(1, store.load().unwrap().len());
+        assert_eq!("Example", store.load().unwrap()[0].title);
+
+        add_bookmark(&mut store, url, Some("Updated"), Some("web"), "", &add_options(OnDuplicate::Update),
+                     &rules).unwrap();
+        let bookmarks = store.load().unwrap();
+        assert_eq!(1, bookmarks.len());
+        assert_eq!("Updated", bookmarks[0].title);
+        assert_eq!(["rust", "web"], bookmarks[0].tags());
+
+        add_bookmark(&mut store, url, Some("Copy"), None, "", &add_options(OnDuplicate::Force), &rules).unwrap();
+        let bookmarks = store.load().unwrap();
+        assert_eq!(2, bookmarks.len());
+        assert_eq!(bookmarks[0].hash, bookmarks[1].hash);
+        assert_ne!(bookmarks[0].label, bookmarks[1].label);
+        assert_eq!("Copy", bookmarks[1].title);
+    }
+
+    #[test]
+    fn dedupe_test() {
+        test_base();
+        let first = tagged("https://example.com/dedupe", "rust");
+        let tracked = tagged("https://example.com/dedupe?utm_source=feed", "web");
+        let mut copy = tagged("https://example.com/dedupe", "docs,rust");
+        copy.set_label("again").unwrap();
+        let other = tagged("https://example.org/dedupe", "rust");
+        let mut store = MemoryStore::from(vec![first.clone(), tracked, copy, other.clone()]);
+
+        assert_eq!(2, dedupe(&mut store, &UrlRules::default()).unwrap());
+        let bookmarks = store.load().unwrap();
+        assert_eq!(2, bookmarks.len());
+        assert_eq!(first.label, bookmarks[0].label);
+        assert_eq!(["rust", "web", "docs"], bookmarks[0].tags());
+        assert_eq!(other, bookmarks[1]);
+        assert_eq!(0, dedupe(&mut store, &UrlRules::default()).unwrap());
+    }
+
+    #[test]
+    fn relabel_test() {
+        let first = tagged("https://example.com/relabel", "rust");
+        let mut later = tagged("https://example.org/relabel", "rust");
+        later.hash = format!("{}0000", first.hash);
+        later.label = first.label.clone();
+        let generated = tagged("https://example.net/relabel", "rust");
+        let mut chosen = tagged("https://example.edu/relabel", "rust");
+        chosen.set_label(&generated.label).unwrap();
+        let mut store = MemoryStore::from(vec![first.clone(), later.clone(), generated.clone(), chosen.clone()]);
+
+        assert_eq!(2, relabel(&mut store).unwrap());
+        let bookmarks = store.load().unwrap();
+        assert_eq!(first, bookmarks[0]);
+        assert_eq!(&later.hash[..6], bookmarks[1].label);
+        assert_eq!(&generated.hash[..6], bookmarks[2].label);
+        assert_eq!(chosen, bookmarks[3]);
+        assert_eq!(0, relabel(&mut store).unwrap());
+    }
+
+    #[test]
+    fn label_taken_test() {
+        let rules = UrlRules::default();
+        let mut taken = tagged("https://example.com/taken", "rust");
+        taken.set_label("taken").unwrap();
+        let other = tagged("https://example.org/taken", "rust");
+        let mut store = MemoryStore::from(vec![taken, other.clone()]);
+
+        let options = AddOptions{label: Some(":taken"), on_duplicate: OnDuplicate::Refuse, fetch_page: false};
+        match add_bookmark(&mut store, "https://example.net/taken", None, None, "", &options, &rules) {
+            Err(Error::LabelTaken(ref label)) if label == "taken" => (),
+            result => panic!("expected the label to be taken, got {:?}", result)
+        }
+        assert_eq!(2, store.load().unwrap().len());
+
+        let edit = App::new("edit").arg(Arg::with_name("new_label").long("label").takes_value(true));
+        let changes = edit.clone().get_matches_from(vec!["edit", "--label", "taken"]);
+        match edit_bookmark(&mut store, &other.label, &changes, &rules) {
+            Err(Error::LabelTaken(ref label)) if label == "taken" => (),
+            result => panic!("expected the label to be taken, got {:?}", result)
+        }
+        assert_eq!(other, store.load().unwrap()[1]);
+
+        let changes = edit.get_matches_from(vec!["edit", "--label", other.label.as_str()]);
+        edit_bookmark(&mut store, &other.label, &changes, &rules).unwrap();
+        assert_eq!(other, store.load().unwrap()[1]);
+    }
+
+    #[test]
+    fn import_bookmarks_test() {
+        let rules = UrlRules::default();
+        let saved = tagged("https://example.com/import", "rust");
+        let new_hash = tagged("https://example.org/import", "rust").hash;
+        let mut chosen = tagged("https://example.edu/import", "rust");
+        chosen.set_label(&new_hash[..5]).unwrap();
+        let mut store = MemoryStore::from(vec![saved.clone(), chosen.clone()]);
+
+        let entry = |url: &str| import::Entry{url: String::from(url), ..import::Entry::default()};
+        let export = Export{
+            entries: vec![entry("https://example.com/import?utm_source=feed"), entry("https://example.org/import"),
+                          entry("https://example.org/import"), entry("https://example.net/import")],
+            rejected: vec![],
+        };
+
+        let dry_run = ImportOptions{visits: false, dry_run: true};
+        let (added, skipped) = import_bookmarks(&mut store, export.clone(), &dry_run, &rules).unwrap();
+        assert_eq!(2, added.len());
+        assert_eq!(2, skipped);
+        assert_eq!(vec![saved.clone(), chosen.clone()], store.load().unwrap());
+
+        let options = ImportOptions{visits: false, dry_run: false};
+        let (added, skipped) = import_bookmarks(&mut store, export, &options, &rules).unwrap();
+        assert_eq!(2, skipped);
+        assert_eq!(vec![saved, chosen, added[0].clone(), added[1].clone()], store.load().unwrap());
+        assert_eq!("https://example.org/import", added[0].url);
+        assert_eq!(&new_hash[..6], added[0].label);
+        assert_eq!("example.org", added[0].title);
+        assert_eq!(["default"], added[0].tags());
+        assert_eq!("https://example.net/import", added[1].url);
+        assert!(added[1].has_generated_label());
+    }
+
+    #[test]
+    fn rename_tag_test() {
+        let mut store = MemoryStore::from(vec![tagged("https://example.com/", "rust,web")]);
+        assert_eq!(0, rename_tag(&mut store, "Rust", "rust").unwrap());
+        assert_eq!(1, rename_tag(&mut store, "web", "www").unwrap());
+        assert_eq!(["rust", "www"], store.load().unwrap()[0].tags());
+    }
+
+    #[test]
+    fn merge_tags_test() {
+        let mut store = MemoryStore::from(vec![tagged("https://example.com/", "rust"),
+                                               tagged("https://example.org/", "rustlang,web")]);
+        assert_eq!(1, merge_tags(&mut store, &["rust", "rustlang"], "rust").unwrap());
+        let bookmarks = store.load().unwrap();
+        assert_eq!(["rust"], bookmarks[0].tags());
+        assert_eq!(["rust", "web"], bookmarks[1].tags());
+    }
+
+    #[test]
+    fn move_images_test() {
+        test_base();
+        save_image("e1e1e1e1", "shared");
+        move_images("e1e1e1e1", "e2e2e2e2", true).unwrap();
+        assert_eq!(Some(String::from("shared")), saved_image("e1e1e1e1"));
+        assert_eq!(Some(String::from("shared")), saved_image("e2e2e2e2"));
+
+        save_image("e3e3e3e3", "new");
+        move_images("e1e1e1e1", "e3e3e3e3", false).unwrap();
+        assert_eq!(None, saved_image("e1e1e1e1"));
+        assert_eq!(Some(String::from("new")), saved_image("e3e3e3e3"));
+
+        move_images("e2e2e2e2", "e4e4e4e4", false).unwrap();
+        assert_eq!(None, saved_image("e2e2e2e2"));
+        assert_eq!(Some(String::from("shared")), saved_image("e4e4e4e4"));
+    }
+
+    #[test]
+    fn find_or_search_test() {
+        let rules = UrlRules::default();
+        let store = MemoryStore::from(vec![tagged("https://github.com/tokio-rs/tokio", "rust"),
+                                           tagged("https://github.com/rust-lang/rust", "rust")]);
+        let label = store.load().unwrap()[0].label.clone();
+        assert_eq!(1, find_or_search(&store, &label, &rules).unwrap().len());
+        assert_eq!(1, find_or_search(&store, "site:github.com tokio", &rules).unwrap().len());
+        assert_eq!(2, find_or_search(&store, "tag:rust", &rules).unwrap().len());
+        assert!(find_or_search(&store, "missing", &rules).unwrap().is_empty());
+        assert!(find_or_search(&store, "", &rules).unwrap().is_empty());
+    }
 }
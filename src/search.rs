@@ -0,0 +1,189 @@
+//! A small query language for finding bookmarks.
+//!
+//! A query is a list of whitespace separated terms, all of which must match:
+//!
+//! - `tag:rust` the bookmark has the tag
+//! - `site:github.com` the url's host is the site or one of its subdomains
+//! - `title:async` the title contains the text
+//! - `url:issues` the url contains the text
+//! - `after:2018-01-01` / `before:2018-01-01` added after or before the date
+//! - anything else is matched against both the title and the url
+//!
+//! Text matching ignores case. Values containing spaces can be quoted
+//! (`title:"async await"`) and any term can be negated with a leading `-`
+//! (`-tag:old`).
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use time;
+use time::Timespec;
+use url::Url;
+
+use {Bookmark, Error, Result};
+
+const DATE: &str = "%Y-%m-%d";
+
+#[derive(Debug, PartialEq)]
+pub enum Term {
+    Text(String),
+    Tag(String),
+    Site(String),
+    Title(String),
+    Url(String),
+    After(Timespec),
+    Before(Timespec),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Condition {
+    pub negated: bool,
+    pub term: Term,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Query {
+    pub conditions: Vec<Condition>,
+}
+
+impl Term {
+    fn new(field: Option<&str>, value: String) -> Result<Term> {
+        if value.is_empty() {
+            return Err(Error::Parse(format!("Missing value for {}:", field.unwrap_or(""))));
+        }
+        Ok(match field {
+            Some("tag") => Term::Tag(::normalize_tag(&value)),
+            Some("site") => Term::Site(value.to_lowercase()),
+            Some("title") => Term::Title(value.to_lowercase()),
+            Some("url") => Term::Url(value.to_lowercase()),
+            Some("after") => Term::After(parse_date(&value)?),
+            Some("before") => Term::Before(parse_date(&value)?),
+            Some(f) => Term::Text(format!("{}:{}", f, value).to_lowercase()),
+            None => Term::Text(value.to_lowercase()),
+        })
+    }
+
+    fn matches(&self, b: &Bookmark) -> bool {
+        match *self {
+            Term::Text(ref text) => b.title.to_lowercase().contains(text)
+                || b.url.to_lowercase().contains(text),
+            Term::Tag(ref tag) => b.has_tag(tag),
+            Term::Site(ref site) => match Url::parse(&b.url).ok().and_then(|u| u.host_str().map(|h| h.to_lowercase())) {
+                Some(host) => host == *site || host.ends_with(&format!(".{}", site)),
+                None => false
+            },
+            Term::Title(ref text) => b.title.to_lowercase().contains(text),
+            Term::Url(ref text) => b.url.to_lowercase().contains(text),
+            Term::After(ref date) => ::timestamp(&b.created_at) >= *date,
+            Term::Before(ref date) => ::timestamp(&b.created_at) < *date,
+        }
+    }
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Query> {
+        let mut conditions: Vec<Condition> = Vec::new();
+        let mut chars = query.chars().peekable();
+
+        loop {
+            while chars.peek().map(|c| c.is_whitespace()) == Some(true) {
+                chars.next();
+            }
+            if chars.peek().is_none() {
+                break
+            }
+
+            let negated = chars.peek() == Some(&'-');
+            if negated {
+                chars.next();
+            }
+
+            let (field, value) = read_term(&mut chars)?;
+            let term = Term::new(field.as_deref(), value)?;
+            conditions.push(Condition{negated, term});
+        }
+        Ok(Query{conditions})
+    }
+
+    /// True if the bookmark satisfies every condition. An empty query
+    /// matches everything.
+    pub fn matches(&self, b: &Bookmark) -> bool {
+        self.conditions.iter().all(|c| c.term.matches(b) != c.negated)
+    }
+}
+
+/// Reads one term up to the next unquoted whitespace, splitting off a
+/// `field:` prefix if one appears before any quotes
+fn read_term(chars: &mut Peekable<Chars>) -> Result<(Option<String>, String)> {
+    let mut field: Option<String> = None;
+    let mut value = String::new();
+    let mut quoted = false;
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            break
+        }
+        chars.next();
+        match c {
+            '"' => {
+                quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(q) => value.push(q),
+                        None => return Err(Error::Parse(String::from("Unterminated quote")))
+                    }
+                }
+            },
+            ':' if field.is_none() && !quoted && !value.is_empty() => {
+                field = Some(value.to_lowercase());
+                value = String::new();
+            },
+            _ => value.push(c)
+        }
+    }
+    Ok((field, value))
+}
+
+fn parse_date(date: &str) -> Result<Timespec> {
+    time::strptime(date, DATE)
+        .map(|t| t.to_timespec())
+        .map_err(|_| Error::Parse(format!("Invalid date {}, expected YYYY-MM-DD", date)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bookmark() -> Bookmark {
+        Bookmark::new_from_line(String::from(
+            "a123|2018-03-01T10:00:00Z|:5|https://github.com/tokio-rs/tokio|Async IO for Rust|rust,async|")).unwrap()
+    }
+
+    #[test]
+    fn parse_test() {
+        let q = Query::parse("tag:Rust -tag:old title:\"async io\" some").unwrap();
+        assert_eq!(vec![
+            Condition{negated: false, term: Term::Tag(String::from("rust"))},
+            Condition{negated: true, term: Term::Tag(String::from("old"))},
+            Condition{negated: false, term: Term::Title(String::from("async io"))},
+            Condition{negated: false, term: Term::Text(String::from("some"))}],
+                   q.conditions);
+
+        assert!(Query::parse("title:\"async").is_err());
+        assert!(Query::parse("after:yesterday").is_err());
+        assert!(Query::parse("tag:").is_err());
+    }
+
+    #[test]
+    fn matches_test() {
+        let b = bookmark();
+        assert!(Query::parse("").unwrap().matches(&b));
+        assert!(Query::parse("tag:rust site:github.com title:\"async io\" tokio").unwrap().matches(&b));
+        assert!(Query::parse("after:2018-01-01 before:2018-03-02").unwrap().matches(&b));
+        assert!(Query::parse("https://github.com").unwrap().matches(&b));
+        assert!(!Query::parse("-tag:async").unwrap().matches(&b));
+        assert!(!Query::parse("site:hub.com").unwrap().matches(&b));
+        assert!(!Query::parse("after:2018-03-02").unwrap().matches(&b));
+    }
+}
@@ -0,0 +1,171 @@
+//! A small local server for the new tab page. Its tiles link through
+//! `/go/<key>/<hash>`, which counts the visit and redirects to the bookmark.
+//! The key is kept in `$RBM_BASE/.bm.key` so that other pages can't add
+//! visits; `/go/<hash>` without it only redirects.
+
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use rbmlib::config::Config;
+use rbmlib::files::random_hex;
+use rbmlib::store::BookmarkStore;
+use rbmlib::{Bookmark, Result};
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Response {
+        Response{status, content_type: "text/plain; charset=utf-8", location: None, body: body.as_bytes().to_vec()}
+    }
+
+    fn not_found() -> Response {
+        Response::text("404 Not Found", "Not found")
+    }
+}
+
+pub fn serve(store: &mut dyn BookmarkStore, config: &Config, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Serving on http://127.0.0.1:{}/", port);
+
+    for stream in listener.incoming() {
+        let handled = stream.map_err(|e| e.into())
+            .and_then(|stream| handle(stream, store, config));
+        if let Err(e) = handled {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+/// The secret part of visit links, created the first time it's needed
+pub fn visit_key() -> Result<String> {
+    let path = format!("{}/.bm.key", rbmlib::base_dir()?);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let key = random_hex()?;
+    match options.open(&path) {
+        Ok(mut f) => {
+            f.write_all(key.as_bytes())?;
+            Ok(key)
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(fs::read_to_string(&path)?.trim().to_string()),
+        Err(e) => Err(e.into())
+    }
+}
+
+fn handle(mut stream: TcpStream, store: &mut dyn BookmarkStore, config: &Config) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Only the request line matters, but the browser expects the headers read
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => respond(path, store, config)
+            .unwrap_or_else(|e| Response::text("500 Internal Server Error", &e.to_string())),
+        _ => Response::text("400 Bad Request", "Only GET is supported")
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+           response.status, response.content_type, response.body.len())?;
+    if let Some(location) = response.location {
+        write!(stream, "Location: {}\r\n", location)?;
+    }
+    write!(stream, "\r\n")?;
+    stream.write_all(&response.body)?;
+    Ok(())
+}
+
+fn respond(path: &str, store: &mut dyn BookmarkStore, config: &Config) -> Result<Response> {
+    let path = path.split('?').next().unwrap_or_default();
+
+    if path == "/" || path == "/bm.html" {
+        let mut bookmarks = store.load()?;
+        config.html_order.sort(&mut bookmarks);
+        let page = rbmlib::html_output_via(bookmarks, Some(&format!("/go/{}/", visit_key()?)))?;
+        return Ok(Response{status: "200 OK", content_type: "text/html; charset=utf-8", location: None,
+                           body: page.into_bytes()});
+    }
+    if let Some(rest) = path.strip_prefix("/go/") {
+        return match rest.find('/') {
+            Some(i) => go(&rest[i + 1..], Some(&rest[..i]), store),
+            None => go(rest, None, store)
+        };
+    }
+    if let Some(name) = path.strip_prefix("/.bm.shots/") {
+        return image(name);
+    }
+    Ok(Response::not_found())
+}
+
+/// Redirects to the bookmark with the hash, counting the visit if the link
+/// has the right key
+fn go(hash: &str, key: Option<&str>, store: &mut dyn BookmarkStore) -> Result<Response> {
+    let found = match key {
+        Some(key) if key == visit_key()? => {
+            store.lock()?;
+            let found = visit(hash, store);
+            store.unlock();
+            found?
+        },
+        _ => store.iter()?.find(|b| b.hash == hash)
+    };
+    let b = match found {
+        Some(b) => b,
+        None => return Ok(Response::not_found())
+    };
+    if b.url.contains(char::is_control) {
+        return Ok(Response::text("400 Bad Request", "The bookmark's url can't be redirected to"));
+    }
+
+    Ok(Response{status: "302 Found", content_type: "text/plain; charset=utf-8", location: Some(b.url.clone()),
+                body: b.url.into_bytes()})
+}
+
+fn visit(hash: &str, store: &mut dyn BookmarkStore) -> Result<Option<Bookmark>> {
+    let b = match store.iter()?.find(|b| b.hash == hash) {
+        Some(b) => b,
+        None => return Ok(None)
+    };
+    let mut visited = b.clone();
+    visited.visit();
+    store.update(&b, &visited)?;
+    Ok(Some(b))
+}
+
+fn image(name: &str) -> Result<Response> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.starts_with('.') {
+        return Ok(Response::not_found());
+    }
+    let content_type = if name.ends_with(".svg") {
+        "image/svg+xml"
+    } else if name.ends_with(".png") {
+        "image/png"
+    } else {
+        "application/octet-stream"
+    };
+
+    match fs::read(format!("{}/.bm.shots/{}", rbmlib::base_dir()?, name)) {
+        Ok(body) => Ok(Response{status: "200 OK", content_type, location: None, body}),
+        Err(_) => Ok(Response::not_found())
+    }
+}
@@ -0,0 +1,375 @@
+//! Bookmarks in a SQLite database, for collections too big to reparse on
+//! every command. Needs the `sqlite` feature.
+//!
+//! Tags, custom images, page metadata and visits live in their own tables,
+//! keyed by the bookmark's row id. Every `Bookmark` field is stored as it
+//! would be written to bm.lnk, so moving between the two is lossless.
+//! Downloaded images are kept by hash, as their files are. `find` uses the
+//! label and hash indexes and reads only the url column, instead of loading
+//! every bookmark.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, Transaction};
+use time;
+
+use files::{Lock, LOCK_TIMEOUT};
+use store::BookmarkStore;
+use urls::UrlRules;
+use {Bookmark, Metadata, Result, Error, ISO_TIME_DATE};
+
+const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS bookmarks (
+    id INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    label TEXT NOT NULL,
+    url TEXT NOT NULL,
+    title TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS bookmarks_hash ON bookmarks (hash);
+CREATE INDEX IF NOT EXISTS bookmarks_label ON bookmarks (label);
+
+CREATE TABLE IF NOT EXISTS tags (
+    bookmark_id INTEGER NOT NULL REFERENCES bookmarks (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (bookmark_id, position)
+);
+CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+
+CREATE TABLE IF NOT EXISTS images (
+    bookmark_id INTEGER PRIMARY KEY REFERENCES bookmarks (id) ON DELETE CASCADE,
+    custom_image TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS metadata (
+    bookmark_id INTEGER PRIMARY KEY REFERENCES bookmarks (id) ON DELETE CASCADE,
+    description TEXT NOT NULL,
+    site_name TEXT NOT NULL,
+    canonical TEXT NOT NULL,
+    lang TEXT NOT NULL,
+    feeds TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS visits (
+    bookmark_id INTEGER PRIMARY KEY REFERENCES bookmarks (id) ON DELETE CASCADE,
+    last_visited TEXT NOT NULL,
+    count INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS image_files (
+    hash TEXT PRIMARY KEY,
+    extension TEXT NOT NULL
+);
+";
+
+pub struct SqliteStore {
+    conn: Connection,
+    /// The database file, for `lock`. None when in memory.
+    path: Option<PathBuf>,
+    lock: Option<Lock>,
+}
+
+impl SqliteStore {
+    /// Opens the database, creating it and its tables if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore> {
+        SqliteStore::init(Connection::open(&path)?, Some(path.as_ref().to_path_buf()))
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore> {
+        SqliteStore::init(Connection::open_in_memory()?, None)
+    }
+
+    fn init(conn: Connection, path: Option<PathBuf>) -> Result<SqliteStore> {
+        conn.busy_timeout(LOCK_TIMEOUT)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStore{conn, path, lock: None})
+    }
+
+    /// The downloaded image recorded for the hash, `png` or `svg`
+    pub fn image_file(&self, hash: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare("SELECT extension FROM image_files WHERE hash = ?1")?;
+        let mut found = stmt.query_map(params![hash], |row| row.get::<_, String>(0))?;
+        Ok(found.next().transpose()?)
+    }
+}
+
+/// Which bookmarks `rows` reads
+enum Rows<'a> {
+    All,
+    Hash(&'a str),
+    Label(&'a str),
+    /// Hashes starting with the text, which must be alphanumeric
+    HashPrefix(&'a str),
+    Ids(&'a [i64]),
+}
+
+impl<'a> Rows<'a> {
+    /// The condition on `bookmarks b`, and the value for its `?1`
+    fn condition(&self) -> (String, Option<String>) {
+        match *self {
+            Rows::All => (String::from("?1 IS NULL"), None),
+            Rows::Hash(hash) => (String::from("b.hash = ?1"), Some(hash.to_string())),
+            Rows::Label(label) => (String::from("b.label = ?1"), Some(label.to_string())),
+            Rows::HashPrefix(prefix) => (String::from("b.hash GLOB ?1"), Some(format!("{}*", prefix))),
+            Rows::Ids(ids) => {
+                let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+                (format!("?1 IS NULL AND b.id IN ({})", ids.join(",")), None)
+            }
+        }
+    }
+}
+
+/// Row ids of the bookmarks whose url is the same as `url` once normalized.
+/// Only the url column is read.
+fn same_url(conn: &Connection, url: &str, rules: &UrlRules) -> Result<Vec<i64>> {
+    let url = rules.normalize(url);
+    let mut ids: Vec<i64> = Vec::new();
+    let mut stmt = conn.prepare("SELECT id, url FROM bookmarks ORDER BY id")?;
+    for row in stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (id, stored): (i64, String) = row?;
+        if rules.normalize(&stored) == url {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// The bookmarks chosen by `which` with their row ids, in insertion order
+fn rows(conn: &Connection, which: Rows) -> Result<Vec<(i64, Bookmark)>> {
+    let (condition, value) = which.condition();
+    let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT t.bookmark_id, t.tag FROM tags t JOIN bookmarks b ON b.id = t.bookmark_id
+         WHERE {} ORDER BY t.bookmark_id, t.position", condition))?;
+    for row in stmt.query_map(params![value], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (id, tag): (i64, String) = row?;
+        tags.entry(id).or_default().push(tag);
+    }
+
+    let mut images: HashMap<i64, String> = HashMap::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT i.bookmark_id, i.custom_image FROM images i JOIN bookmarks b ON b.id = i.bookmark_id
+         WHERE {}", condition))?;
+    for row in stmt.query_map(params![value], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (id, custom_image): (i64, String) = row?;
+        images.insert(id, custom_image);
+    }
+
+    let mut metadata: HashMap<i64, Metadata> = HashMap::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.bookmark_id, m.description, m.site_name, m.canonical, m.lang, m.feeds
+         FROM metadata m JOIN bookmarks b ON b.id = m.bookmark_id
+         WHERE {}", condition))?;
+    let found = stmt.query_map(params![value], |row| {
+        let feeds: String = row.get(5)?;
+        Ok((row.get::<_, i64>(0)?, Metadata{
+            description: row.get(1)?,
+            site_name: row.get(2)?,
+            canonical: row.get(3)?,
+            lang: row.get(4)?,
+            feeds: feeds.split_whitespace().map(String::from).collect(),
+        }))
+    })?;
+    for row in found {
+        let (id, m) = row?;
+        metadata.insert(id, m);
+    }
+
+    let mut visits: HashMap<i64, (String, u32)> = HashMap::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT v.bookmark_id, v.last_visited, v.count FROM visits v JOIN bookmarks b ON b.id = v.bookmark_id
+         WHERE {}", condition))?;
+    for row in stmt.query_map(params![value], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))? {
+        let (id, last_visited, count): (i64, String, u32) = row?;
+        visits.insert(id, (last_visited, count));
+    }
+
+    let mut bookmarks: Vec<(i64, Bookmark)> = Vec::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT b.id, b.hash, b.created_at, b.label, b.url, b.title FROM bookmarks b
+         WHERE {} ORDER BY b.id", condition))?;
+    let found = stmt.query_map(params![value], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?, row.get::<_, String>(4)?, row.get::<_, String>(5)?))
+    })?;
+    for row in found {
+        let (id, hash, created_at, label, url, title) = row?;
+        let created_at = time::strptime(&created_at, ISO_TIME_DATE)?;
+        let tags = tags.remove(&id).unwrap_or_default();
+        let custom_image = images.remove(&id).unwrap_or_default();
+        let metadata = metadata.remove(&id).unwrap_or_default();
+        let (last_visited, visit_count) = visits.remove(&id).unwrap_or_default();
+        let last_visited = match last_visited.as_str() {
+            "" => None,
+            visited => Some(time::strptime(visited, ISO_TIME_DATE)?)
+        };
+        bookmarks.push((id, Bookmark{hash, created_at, label, url, title, tags, custom_image, metadata,
+                                     last_visited, visit_count}));
+    }
+    Ok(bookmarks)
+}
+
+/// Replaces the tags, custom image, metadata and visits stored for a row
+fn write_details(tx: &Transaction, id: i64, b: &Bookmark) -> Result<()> {
+    tx.execute("DELETE FROM tags WHERE bookmark_id = ?1", params![id])?;
+    for (position, tag) in b.tags.iter().enumerate() {
+        tx.execute("INSERT INTO tags (bookmark_id, position, tag) VALUES (?1, ?2, ?3)",
+                   params![id, position as i64, tag])?;
+    }
+
+    tx.execute("DELETE FROM images WHERE bookmark_id = ?1", params![id])?;
+    if !b.custom_image.is_empty() {
+        tx.execute("INSERT INTO images (bookmark_id, custom_image) VALUES (?1, ?2)",
+                   params![id, b.custom_image])?;
+    }
+
+    tx.execute("DELETE FROM metadata WHERE bookmark_id = ?1", params![id])?;
+    let m = &b.metadata;
+    if !m.is_empty() {
+        tx.execute("INSERT INTO metadata (bookmark_id, description, site_name, canonical, lang, feeds)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                   params![id, m.description, m.site_name, m.canonical, m.lang, m.feeds.join(" ")])?;
+    }
+
+    tx.execute("DELETE FROM visits WHERE bookmark_id = ?1", params![id])?;
+    if b.last_visited.is_some() || b.visit_count > 0 {
+        let last_visited = b.last_visited.map(|t| t.rfc3339().to_string()).unwrap_or_default();
+        tx.execute("INSERT INTO visits (bookmark_id, last_visited, count) VALUES (?1, ?2, ?3)",
+                   params![id, last_visited, b.visit_count])?;
+    }
+    Ok(())
+}
+
+fn insert_row(tx: &Transaction, b: &Bookmark) -> Result<()> {
+    tx.execute("INSERT INTO bookmarks (hash, created_at, label, url, title) VALUES (?1, ?2, ?3, ?4, ?5)",
+               params![b.hash, b.created_at.rfc3339().to_string(), b.label, b.url, b.title])?;
+    let id = tx.last_insert_rowid();
+    write_details(tx, id, b)
+}
+
+/// The row id of the first stored bookmark equal to `b` that isn't in `used`
+fn find_row(conn: &Connection, b: &Bookmark, used: &[i64]) -> Result<i64> {
+    rows(conn, Rows::Hash(&b.hash))?.into_iter()
+        .find(|(id, stored)| stored == b && !used.contains(id))
+        .map(|(id, _)| id)
+        .ok_or_else(|| Error::NotFound(b.label.clone()))
+}
+
+impl BookmarkStore for SqliteStore {
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Bookmark>>> {
+        let bookmarks: Vec<Bookmark> = rows(&self.conn, Rows::All)?.into_iter().map(|(_, b)| b).collect();
+        Ok(Box::new(bookmarks.into_iter()))
+    }
+
+    fn find(&self, query: &str, rules: &UrlRules) -> Result<Vec<Bookmark>> {
+        let label = query.strip_prefix(':').unwrap_or(query);
+        let mut found = rows(&self.conn, Rows::Label(label))?;
+        if found.is_empty() && !query.is_empty() {
+            found = rows(&self.conn, Rows::Ids(&same_url(&self.conn, query, rules)?))?;
+            // GLOB would treat anything else as a pattern, and hashes are hex
+            if query.chars().all(|c| c.is_ascii_alphanumeric()) {
+                found.extend(rows(&self.conn, Rows::HashPrefix(query))?);
+                found.sort_by_key(|&(id, _)| id);
+                found.dedup_by_key(|&mut (id, _)| id);
+            }
+        }
+        Ok(found.into_iter().map(|(_, b)| b).collect())
+    }
+
+    fn insert(&mut self, bookmark: &Bookmark) -> Result<()> {
+        self.insert_all(::std::slice::from_ref(bookmark))
+    }
+
+    fn insert_all(&mut self, bookmarks: &[Bookmark]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for b in bookmarks {
+            insert_row(&tx, b)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update_all(&mut self, changes: &[(Bookmark, Bookmark)]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let mut used: Vec<i64> = Vec::new();
+
+        for (old, new) in changes {
+            let id = find_row(&tx, old, &used)?;
+            tx.execute("UPDATE bookmarks SET hash = ?1, created_at = ?2, label = ?3, url = ?4, title = ?5
+                        WHERE id = ?6",
+                       params![new.hash, new.created_at.rfc3339().to_string(), new.label, new.url, new.title, id])?;
+            write_details(&tx, id, new)?;
+            used.push(id);
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, bookmark: &Bookmark) -> Result<()> {
+        let id = find_row(&self.conn, bookmark, &[])?;
+        self.conn.execute("DELETE FROM bookmarks WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Single writes are transactions already; this takes `<path>.lock` as
+    /// bm.lnk does, so the reads before them are covered too
+    fn lock(&mut self) -> Result<()> {
+        if let (None, Some(path)) = (&self.lock, &self.path) {
+            self.lock = Some(Lock::acquire(path)?);
+        }
+        Ok(())
+    }
+
+    fn record_image(&mut self, hash: &str, extension: Option<&str>) -> Result<()> {
+        match extension {
+            Some(extension) => self.conn.execute(
+                "INSERT OR REPLACE INTO image_files (hash, extension) VALUES (?1, ?2)", params![hash, extension])?,
+            None => self.conn.execute("DELETE FROM image_files WHERE hash = ?1", params![hash])?
+        };
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::tests::exercise;
+
+    #[test]
+    fn sqlite_store_test() {
+        exercise(&mut SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn lossless_test() {
+        let line = "a123|2017-12-18T11:46:29+05:30|:5|https://www.example.com/|Foo \\| Bar|tag2,tag1|test|\
+                    A page|Example|https://example.com/||https://example.com/rss|2019-01-02T03:04:05-08:00|3";
+        let b = Bookmark::new_from_line(String::from(line)).unwrap();
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        store.insert(&b).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(vec![b], loaded);
+        assert_eq!(line, loaded[0].output());
+    }
+
+    #[test]
+    fn image_file_test() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        assert_eq!(None, store.image_file("a123").unwrap());
+        store.record_image("a123", Some("png")).unwrap();
+        store.record_image("a123", Some("svg")).unwrap();
+        assert_eq!(Some(String::from("svg")), store.image_file("a123").unwrap());
+        store.record_image("a123", None).unwrap();
+        assert_eq!(None, store.image_file("a123").unwrap());
+    }
+}
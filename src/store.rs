@@ -0,0 +1,325 @@
+//! Where bookmarks are kept.
+//!
+//! Bookmarks don't have an id of their own, so `update` and `delete` find the
+//! stored bookmark equal to the one passed in. Identical bookmarks are
+//! interchangeable, so the first match is used.
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use files::{write_atomic, Lock};
+use urls::UrlRules;
+use {Bookmark, Error, Result};
+
+pub trait BookmarkStore {
+    /// Every readable bookmark, in store order
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Bookmark>>>;
+
+    fn load(&self) -> Result<Vec<Bookmark>> {
+        Ok(self.iter()?.collect())
+    }
+
+    /// Bookmarks with the label, or failing that a hash starting with the
+    /// query or a url that is the same once normalized, in store order.
+    /// Labels may be given with or without the leading `:`.
+    fn find(&self, query: &str, rules: &UrlRules) -> Result<Vec<Bookmark>> {
+        let bookmarks = self.load()?;
+        let label = query.strip_prefix(':').unwrap_or(query);
+        if bookmarks.iter().any(|b| b.label == label) {
+            return Ok(bookmarks.into_iter().filter(|b| b.label == label).collect());
+        }
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let url = rules.normalize(query);
+        Ok(bookmarks.into_iter()
+           .filter(|b| b.hash.starts_with(query) || rules.normalize(&b.url) == url)
+           .collect())
+    }
+
+    fn insert(&mut self, bookmark: &Bookmark) -> Result<()>;
+
+    fn insert_all(&mut self, bookmarks: &[Bookmark]) -> Result<()> {
+        for b in bookmarks {
+            self.insert(b)?;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, old: &Bookmark, new: &Bookmark) -> Result<()> {
+        self.update_all(&[(old.clone(), new.clone())])
+    }
+
+    /// Replaces each `(old, new)` pair in one go
+    fn update_all(&mut self, changes: &[(Bookmark, Bookmark)]) -> Result<()>;
+
+    fn delete(&mut self, bookmark: &Bookmark) -> Result<()>;
+
+    /// Notes which downloaded image the hash has, `png` or `svg`, or that it
+    /// has none. Stores that don't keep image details ignore it.
+    fn record_image(&mut self, _hash: &str, _extension: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Keeps other rbm runs out until `unlock`, so a read and the writes
+    /// based on it happen as one change
+    fn lock(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&mut self) {}
+}
+
+/// The bm.lnk file, one bookmark per line. Lines that can't be read are
+/// skipped when loading and kept when the file is rewritten.
+///
+/// Changes hold a lock on `bm.lnk.lock` and rewrites replace the file
+/// atomically, so concurrent runs can't interleave.
+pub struct FileStore {
+    path: PathBuf,
+    lock: Option<Lock>,
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileStore {
+        FileStore{path: path.as_ref().to_path_buf(), lock: None}
+    }
+
+    /// The lock for a single write, unless `lock` already holds it
+    fn write_lock(&self) -> Result<Option<Lock>> {
+        match self.lock {
+            Some(_) => Ok(None),
+            None => Lock::acquire(&self.path).map(Some)
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read_lines(&self) -> Result<Vec<String>> {
+        let f = File::open(&self.path)?;
+        Ok(BufReader::new(f).lines().collect::<::std::io::Result<Vec<String>>>()?)
+    }
+
+    fn write_lines(&self, lines: &[String]) -> Result<()> {
+        let mut contents = String::new();
+        for line in lines {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+        write_atomic(&self.path, contents.as_bytes())
+    }
+}
+
+impl BookmarkStore for FileStore {
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Bookmark>>> {
+        let lines = self.read_lines()?;
+        Ok(Box::new(lines.into_iter()
+                    .filter_map(|line| Bookmark::new_from_line(line).ok())))
+    }
+
+    fn insert(&mut self, bookmark: &Bookmark) -> Result<()> {
+        self.insert_all(::std::slice::from_ref(bookmark))
+    }
+
+    fn insert_all(&mut self, bookmarks: &[Bookmark]) -> Result<()> {
+        let _lock = self.write_lock()?;
+        let mut f = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        for b in bookmarks {
+            writeln!(f, "{}", b.output())?;
+        }
+        Ok(())
+    }
+
+    fn update_all(&mut self, changes: &[(Bookmark, Bookmark)]) -> Result<()> {
+        let _lock = self.write_lock()?;
+        let mut pending: Vec<&(Bookmark, Bookmark)> = changes.iter().collect();
+        let mut lines = self.read_lines()?;
+
+        for line in lines.iter_mut() {
+            if pending.is_empty() {
+                break
+            }
+            if let Ok(b) = Bookmark::new_from_line(line.clone()) {
+                if let Some(i) = pending.iter().position(|(old, _)| *old == b) {
+                    *line = pending.remove(i).1.output();
+                }
+            }
+        }
+
+        if let Some((old, _)) = pending.first() {
+            return Err(Error::NotFound(old.label.clone()));
+        }
+        self.write_lines(&lines)
+    }
+
+    fn delete(&mut self, bookmark: &Bookmark) -> Result<()> {
+        let _lock = self.write_lock()?;
+        let mut lines = self.read_lines()?;
+
+        let position = lines.iter()
+            .position(|line| Bookmark::new_from_line(line.clone()).ok().as_ref() == Some(bookmark));
+        match position {
+            Some(i) => {
+                lines.remove(i);
+                self.write_lines(&lines)
+            },
+            None => Err(Error::NotFound(bookmark.label.clone()))
+        }
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        if self.lock.is_none() {
+            self.lock = Some(Lock::acquire(&self.path)?);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+    }
+}
+
+/// Keeps bookmarks in memory only, for tests and tools that build a
+/// collection before saving it elsewhere
+#[derive(Default)]
+pub struct MemoryStore {
+    bookmarks: Vec<Bookmark>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl From<Vec<Bookmark>> for MemoryStore {
+    fn from(bookmarks: Vec<Bookmark>) -> MemoryStore {
+        MemoryStore{bookmarks}
+    }
+}
+
+impl BookmarkStore for MemoryStore {
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Bookmark>>> {
+        Ok(Box::new(self.bookmarks.clone().into_iter()))
+    }
+
+    fn insert(&mut self, bookmark: &Bookmark) -> Result<()> {
+        self.bookmarks.push(bookmark.clone());
+        Ok(())
+    }
+
+    fn update_all(&mut self, changes: &[(Bookmark, Bookmark)]) -> Result<()> {
+        let mut updated = self.bookmarks.clone();
+        let mut done = vec![false; updated.len()];
+
+        for (old, new) in changes {
+            let position = (0..updated.len()).find(|&i| !done[i] && updated[i] == *old);
+            match position {
+                Some(i) => {
+                    updated[i] = new.clone();
+                    done[i] = true;
+                },
+                None => return Err(Error::NotFound(old.label.clone()))
+            }
+        }
+        self.bookmarks = updated;
+        Ok(())
+    }
+
+    fn delete(&mut self, bookmark: &Bookmark) -> Result<()> {
+        match self.bookmarks.iter().position(|b| b == bookmark) {
+            Some(i) => {
+                self.bookmarks.remove(i);
+                Ok(())
+            },
+            None => Err(Error::NotFound(bookmark.label.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use fs2::FileExt;
+
+    fn bookmark(url: &str) -> Bookmark {
+        Bookmark::new_from_input(String::from(url), String::from("Example"), String::from("tag1"), String::new())
+    }
+
+    pub fn exercise(store: &mut dyn BookmarkStore) {
+        let a = bookmark("https://www.example.com/");
+        let b = bookmark("https://www.example.org/");
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.insert(&a).unwrap();
+        assert_eq!(vec![a.clone(), b.clone(), a.clone()], store.load().unwrap());
+
+        let mut renamed = a.clone();
+        renamed.set_title("Renamed");
+        store.update(&a, &renamed).unwrap();
+        assert_eq!(vec![renamed.clone(), b.clone(), a.clone()], store.load().unwrap());
+
+        store.delete(&a).unwrap();
+        assert_eq!(vec![renamed.clone(), b.clone()], store.load().unwrap());
+        assert!(store.delete(&a).is_err());
+        assert!(store.update(&a, &b).is_err());
+
+        let rules = UrlRules::default();
+        let mut labelled = b.clone();
+        labelled.set_label(&renamed.hash[..6]).unwrap();
+        store.insert(&labelled).unwrap();
+        assert_eq!(vec![labelled.clone()], store.find(&renamed.hash[..6], &rules).unwrap());
+        assert_eq!(vec![labelled.clone()], store.find(&format!(":{}", &renamed.hash[..6]), &rules).unwrap());
+        assert_eq!(vec![renamed.clone()], store.find(&renamed.hash[..7], &rules).unwrap());
+        assert_eq!(vec![b.clone(), labelled.clone()], store.find("HTTPS://www.Example.org?utm_source=feed", &rules).unwrap());
+        assert!(store.find("", &rules).unwrap().is_empty());
+        assert!(store.find("%", &rules).unwrap().is_empty());
+        store.delete(&labelled).unwrap();
+
+        store.lock().unwrap();
+        store.insert(&a).unwrap();
+        store.delete(&a).unwrap();
+        store.unlock();
+    }
+
+    #[test]
+    fn memory_store_test() {
+        exercise(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn file_store_test() {
+        let path = env::temp_dir().join(format!("rbm-file-store-test-{}.lnk", ::std::process::id()));
+        fs::write(&path, "").unwrap();
+
+        exercise(&mut FileStore::new(&path));
+
+        fs::write(&path, "not a bookmark\n").unwrap();
+        let mut store = FileStore::new(&path);
+        let a = bookmark("https://www.example.com/");
+        store.insert(&a).unwrap();
+        store.update(&a, &bookmark("https://www.example.net/")).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().starts_with("not a bookmark\n"));
+
+        store.lock().unwrap();
+        let other = OpenOptions::new().write(true).open(path.with_extension("lnk.lock")).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        store.unlock();
+        assert!(other.try_lock_exclusive().is_ok());
+
+        fs::write(&path, b"not utf-8 \xff\n").unwrap();
+        assert!(store.iter().is_err());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lnk.lock")).unwrap();
+    }
+}
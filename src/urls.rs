@@ -0,0 +1,91 @@
+//! Normalizing urls before they are hashed, so the same page saved from
+//! different links gets the same hash and icon.
+
+use url::Url;
+
+/// How urls are normalized. The `url` crate always lowercases the scheme and
+/// host, drops default ports and adds a missing root path; these rules go
+/// further.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "derive", serde(default))]
+pub struct UrlRules {
+    /// Treat `http` as `https`, so both get the same hash
+    pub ignore_scheme: bool,
+    /// Drop everything after `#`
+    pub drop_fragment: bool,
+    /// Query parameters to remove. A trailing `*` matches any parameter
+    /// starting with the rest, so `utm_*` covers `utm_source`.
+    pub strip_params: Vec<String>,
+}
+
+impl Default for UrlRules {
+    fn default() -> UrlRules {
+        UrlRules{
+            ignore_scheme: false,
+            drop_fragment: false,
+            strip_params: vec![String::from("utm_*"), String::from("fbclid"), String::from("gclid")],
+        }
+    }
+}
+
+impl UrlRules {
+    fn strips(&self, param: &str) -> bool {
+        let param = param.to_lowercase();
+        self.strip_params.iter().any(|rule| match rule.strip_suffix('*') {
+            Some(prefix) => param.starts_with(&prefix.to_lowercase()),
+            None => param == rule.to_lowercase()
+        })
+    }
+
+    /// The url with the rules applied. Parameters that are kept are left
+    /// exactly as written. Urls that don't parse are only trimmed.
+    pub fn normalize(&self, url: &str) -> String {
+        let mut parsed = match Url::parse(url.trim()) {
+            Ok(parsed) => parsed,
+            Err(_) => return url.trim().to_string()
+        };
+
+        if self.ignore_scheme && parsed.scheme() == "http" {
+            // Only fails for special schemes changing to non-special ones
+            let _ = parsed.set_scheme("https");
+        }
+        if self.drop_fragment {
+            parsed.set_fragment(None);
+        }
+
+        let query = parsed.query().map(|query| {
+            query.split('&')
+                .filter(|pair| !pair.is_empty() && !self.strips(pair.split('=').next().unwrap_or("")))
+                .collect::<Vec<&str>>()
+                .join("&")
+        });
+        if let Some(query) = query {
+            parsed.set_query(if query.is_empty() { None } else { Some(&query) });
+        }
+
+        parsed.into_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_test() {
+        let rules = UrlRules::default();
+        assert_eq!("https://x.com/", rules.normalize("HTTPS://X.com:443"));
+        assert_eq!("https://x.com/", rules.normalize("https://x.com/?utm_source=foo&UTM_Medium=bar&fbclid=1"));
+        assert_eq!("https://x.com/a?q=a%20b&page=2#top", rules.normalize("https://x.com/a?q=a%20b&gclid=2&page=2#top"));
+        assert_eq!("not a url", rules.normalize(" not a url "));
+
+        let rules = UrlRules{drop_fragment: true, strip_params: vec![String::from("ref")], ..UrlRules::default()};
+        assert_eq!("http://x.com/?utm_source=foo", rules.normalize("http://x.com?utm_source=foo&ref=hn#top"));
+
+        let rules = UrlRules{ignore_scheme: true, ..UrlRules::default()};
+        assert_eq!(rules.normalize("https://x.com/"), rules.normalize("http://x.com"));
+        assert_eq!("https://x.com:8080/", rules.normalize("http://x.com:8080"));
+        assert_eq!("ftp://x.com/", rules.normalize("ftp://x.com"));
+    }
+}